@@ -0,0 +1,8 @@
+import os
+
+
+def config_dir(config_file):
+    settings = {"verbose": True}
+    if settings.has_key("verbose"):
+        print("looking up the parent of", config_file)
+    return os.path.dirname(config_file)
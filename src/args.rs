@@ -1,4 +1,6 @@
-use clap::{Args, Parser, Subcommand};
+use crate::socket::Address;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
@@ -6,10 +8,15 @@ use std::path::PathBuf;
 pub(crate) struct Cli {
     #[command(subcommand)]
     pub command: Command,
+
+    /// Path to the KDL config file, ignored if it does not exist.
+    #[arg(long, global = true, default_value = "./silos.kdl")]
+    pub(crate) config: PathBuf,
 }
 
+/// The embedding model and the snippets it indexes.
 #[derive(Args, Debug)]
-pub(crate) struct Lsp {
+pub(crate) struct Model {
     /// Run on the Nth GPU device.
     #[arg(long)]
     pub(crate) gpu: Option<usize>,
@@ -28,22 +35,168 @@ pub(crate) struct Lsp {
 }
 
 #[derive(Args, Debug)]
-pub struct DumpExpression {
+pub(crate) struct Lsp {
+    #[command(flatten)]
+    pub(crate) model: Model,
+
+    /// Serve editors connecting to this address or Unix socket instead of stdio, sharing one model between them.
+    #[arg(long)]
+    pub(crate) listen: Option<Address>,
+
+    /// Relay stdio to a server started with `--listen` instead of loading a model.
+    #[arg(long, conflicts_with = "listen")]
+    pub(crate) connect: Option<Address>,
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct Generate {
+    #[command(flatten)]
+    pub(crate) model: Model,
+
+    /// The language to find snippets for.
+    #[arg(long)]
+    pub(crate) lang: String,
+
+    /// What the code should do.
+    pub(crate) prompt: String,
+
+    /// How many snippets to print, closest first.
+    #[arg(long, default_value_t = 5)]
+    pub(crate) top_k: usize,
+
+    /// Print the snippets as JSON.
+    #[arg(long)]
+    pub(crate) json: bool,
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct Refactor {
+    #[command(flatten)]
+    pub(crate) model: Model,
+
+    /// Parse the source as this language instead of going by its file name.
+    #[arg(long)]
+    pub(crate) lang: Option<String>,
+
+    /// What the refactor should do.
+    pub(crate) prompt: String,
+
+    /// Path to the source file, or `-` to read it from stdin.
+    #[arg(default_value = "-")]
+    pub(crate) path: PathBuf,
+
+    /// How many refactors to try, closest first.
+    #[arg(long, default_value_t = 1)]
+    pub(crate) top_k: usize,
+
+    /// Print a unified diff instead of the refactored source.
+    #[arg(long)]
+    pub(crate) diff: bool,
+
+    /// Print the refactors as JSON, along with their diffs.
+    #[arg(long, conflicts_with = "diff")]
+    pub(crate) json: bool,
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct Serve {
+    #[command(flatten)]
+    pub(crate) model: Model,
+
+    /// The address to serve HTTP on.
+    #[arg(long, default_value = "127.0.0.1:7878")]
+    pub(crate) listen: std::net::SocketAddr,
+}
+
+/// A source file to read, and the language to parse it as.
+#[derive(Args, Debug)]
+pub struct Input {
+    /// Path to the source file, or `-` to read it from stdin.
     pub path: PathBuf,
+
+    /// Parse the source as this language instead of going by its file name.
+    #[arg(long)]
+    pub lang: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct DumpExpression {
+    #[command(flatten)]
+    pub input: Input,
+
+    /// Print an indented tree with field names, positions and the text of leaves.
+    #[arg(long)]
+    pub tree: bool,
+
+    /// Include anonymous nodes such as punctuation and keywords.
+    #[arg(long)]
+    pub anonymous: bool,
+
+    /// Only show the nodes within a line or an inclusive range of lines, as in `12` or `12:20`.
+    #[arg(long, value_parser = parse_lines)]
+    pub lines: Option<RangeInclusive<usize>>,
+
+    /// Only show the smallest named node around a byte offset.
+    #[arg(long, conflicts_with = "lines")]
+    pub byte: Option<usize>,
+
+    /// Print the nodes as JSON.
+    #[arg(long, conflicts_with = "tree")]
+    pub json: bool,
+}
+
+fn parse_lines(lines: &str) -> Result<RangeInclusive<usize>, String> {
+    let (first, last) = lines.split_once(':').unwrap_or((lines, lines));
+    let parse = |line: &str| {
+        line.trim()
+            .parse::<usize>()
+            .map_err(|e| format!("invalid line `{line}`: {e}"))
+    };
+    let (first, last) = (parse(first)?, parse(last)?);
+    if first == 0 || last < first {
+        return Err(format!("`{lines}` is not a range of lines counting from 1"));
+    }
+    Ok(first..=last)
 }
 
 #[derive(Args, Debug)]
 pub struct ShowCaptures {
-    pub path: PathBuf,
+    #[command(flatten)]
+    pub input: Input,
     pub expression: String,
+
+    /// How to print the matches.
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    pub format: Format,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Format {
+    Json,
+    Table,
+    Text,
 }
 
 #[derive(Args, Debug)]
 pub struct DryRun {
-    pub path: PathBuf,
+    #[command(flatten)]
+    pub input: Input,
     pub edit_file: PathBuf,
 }
 
+#[derive(Args, Debug)]
+pub struct InferRule {
+    /// A source file with the code as it is.
+    pub before: PathBuf,
+    /// The same file with the code as it should become.
+    pub after: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct Repl {
+    pub path: PathBuf,
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Ast {
     /// Dump the S expression for a given source file
@@ -53,6 +206,12 @@ pub enum Ast {
 
     /// Test your edit snippets on a sample file
     DryRun(DryRun),
+
+    /// Draft an edit snippet from a file before and after the change
+    InferRule(InferRule),
+
+    /// Try queries and substitutes interactively on a source file
+    Repl(Repl),
 }
 
 #[derive(Subcommand, Debug)]
@@ -62,9 +221,15 @@ pub enum Command {
     Ast(Ast),
     /// spawn a language server for use with a text editor
     Lsp(Lsp),
+    /// print the generate snippets closest to a prompt
+    Generate(Generate),
+    /// refactor a source file with the snippet closest to a prompt
+    Refactor(Refactor),
+    /// serve searches and refactors as JSON over HTTP
+    Serve(Serve),
 }
 
-impl Lsp {
+impl Model {
     pub(crate) fn resolve_model_and_revision(&self) -> (String, String) {
         let default_model = "sentence-transformers/all-MiniLM-L6-v2".to_string();
         let default_revision = "refs/pr/21".to_string();
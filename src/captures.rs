@@ -0,0 +1,122 @@
+use anyhow::Result;
+use serde_json::json;
+
+use crate::args::Format;
+use crate::mutation::{CapturedNode, QueryCooked};
+
+/// One captured node of one match, quantified captures giving a row per node.
+struct Row<'a> {
+    matched: usize,
+    pattern: usize,
+    name: &'a str,
+    node: &'a CapturedNode,
+}
+
+/// Lists every captured node of every match in `format`. The text and table
+/// formats count lines and columns from 1, JSON from 0 as tree-sitter does.
+pub fn render(cooked: &[QueryCooked], source: &str, format: Format) -> Result<String> {
+    let rows: Vec<_> = cooked
+        .iter()
+        .enumerate()
+        .flat_map(|(i, matched)| {
+            let mut rows: Vec<_> = matched
+                .captures
+                .iter()
+                .flat_map(|(name, captured)| {
+                    captured.nodes.iter().map(move |node| Row {
+                        matched: i + 1,
+                        pattern: matched.pattern,
+                        name,
+                        node,
+                    })
+                })
+                .collect();
+            rows.sort_by_key(|row| (row.node.range.start, row.name));
+            rows
+        })
+        .collect();
+    let text = |row: &Row| &source[row.node.range.clone()];
+
+    Ok(match format {
+        Format::Json => {
+            let matches: Vec<_> = cooked
+                .iter()
+                .enumerate()
+                .map(|(i, matched)| {
+                    let captures: Vec<_> = rows
+                        .iter()
+                        .filter(|row| row.matched == i + 1)
+                        .map(|row| {
+                            let node = row.node;
+                            json!({
+                                "name": row.name,
+                                "kind": node.kind,
+                                "start": { "row": node.start.row, "column": node.start.column, "byte": node.range.start },
+                                "end": { "row": node.end.row, "column": node.end.column, "byte": node.range.end },
+                                "text": text(row),
+                            })
+                        })
+                        .collect();
+                    json!({ "pattern": matched.pattern, "captures": captures })
+                })
+                .collect();
+            serde_json::to_string_pretty(&matches)? + "\n"
+        }
+        Format::Table => {
+            let header = [
+                "match", "pattern", "capture", "kind", "start", "end", "bytes", "text",
+            ]
+            .map(String::from);
+            let cells: Vec<[String; 8]> = std::iter::once(header)
+                .chain(rows.iter().map(|row| {
+                    let node = row.node;
+                    [
+                        row.matched.to_string(),
+                        row.pattern.to_string(),
+                        format!("@{}", row.name),
+                        node.kind.to_string(),
+                        format!("{}:{}", node.start.row + 1, node.start.column + 1),
+                        format!("{}:{}", node.end.row + 1, node.end.column + 1),
+                        format!("{}..{}", node.range.start, node.range.end),
+                        format!("{:?}", text(row)),
+                    ]
+                }))
+                .collect();
+            let widths: Vec<_> = (0..8)
+                .map(|column| cells.iter().map(|row| row[column].chars().count()).max())
+                .map(Option::unwrap_or_default)
+                .collect();
+            let mut table = String::new();
+            for row in &cells {
+                let line: Vec<_> = row
+                    .iter()
+                    .zip(&widths)
+                    .map(|(cell, width)| format!("{cell:width$}"))
+                    .collect();
+                table.push_str(line.join("  ").trim_end());
+                table.push('\n');
+            }
+            table
+        }
+        Format::Text => {
+            let mut output = String::new();
+            for (i, matched) in cooked.iter().enumerate() {
+                output.push_str(&format!("match {} (pattern {})\n", i + 1, matched.pattern));
+                for row in rows.iter().filter(|row| row.matched == i + 1) {
+                    let node = row.node;
+                    output.push_str(&format!(
+                        "  @{} {} {}:{}-{}:{} {:?}\n",
+                        row.name,
+                        node.kind,
+                        node.start.row + 1,
+                        node.start.column + 1,
+                        node.end.row + 1,
+                        node.end.column + 1,
+                        text(row)
+                    ));
+                }
+            }
+            output
+        }
+    })
+}
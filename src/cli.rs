@@ -0,0 +1,135 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, bail};
+use serde_json::json;
+use similar::TextDiff;
+
+use crate::args;
+use crate::state::{self, State, Workspace};
+
+/// Prints the snippets closest to the prompt with their distances, or JSON.
+pub fn generate(appstate: &State, generate: &args::Generate) -> Result<String> {
+    let suggestions = appstate.generate(&generate.lang, &generate.prompt, generate.top_k)?;
+
+    if generate.json {
+        let suggestions: Vec<_> = suggestions
+            .iter()
+            .map(|s| json!({ "snippet": s.snippet, "distance": s.distance, "code": s.code }))
+            .collect();
+        return Ok(serde_json::to_string_pretty(&suggestions)? + "\n");
+    }
+    let mut output = String::new();
+    for suggestion in suggestions {
+        output.push_str(&format!(
+            "{} (distance {:.3})\n",
+            suggestion.snippet, suggestion.distance
+        ));
+        for line in suggestion.code.lines() {
+            output.push_str(&format!("    {line}\n"));
+        }
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+/// Refactors the source with the snippets closest to the prompt. Prints the
+/// refactored source, a diff of it and of any other files changed, or JSON.
+pub fn refactor(appstate: &State, refactor: &args::Refactor) -> Result<String> {
+    let path = &refactor.path;
+    let language = state::language_for(path, refactor.lang.as_deref())?;
+    let body = String::from_utf8(state::read_path(path)?)?;
+
+    // other files a refactor may change are looked for from here down
+    let root = std::env::current_dir()?;
+    let workspace = Workspace {
+        current: (path != Path::new("-"))
+            .then(|| std::fs::canonicalize(path))
+            .transpose()?,
+        root: Some(root.clone()),
+        open: Default::default(),
+    };
+    let suggestions = appstate.refactor(
+        &language.name,
+        &refactor.prompt,
+        &body,
+        &(0..body.len()),
+        refactor.top_k,
+        &workspace,
+    )?;
+    if suggestions.is_empty() {
+        bail!("no refactor for `{}` applies", refactor.prompt);
+    }
+    let relative = |path: &Path| -> PathBuf {
+        path.strip_prefix(&root)
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|_| path.to_path_buf())
+    };
+
+    if refactor.json {
+        let suggestions: Vec<_> = suggestions
+            .iter()
+            .map(|s| {
+                let elsewhere: Vec<_> = s
+                    .elsewhere
+                    .iter()
+                    .map(|change| {
+                        let path = relative(&change.path);
+                        json!({
+                            "path": path,
+                            "code": change.code,
+                            "diff": diff(&path, &change.original, &change.code),
+                        })
+                    })
+                    .collect();
+                json!({
+                    "snippet": s.snippet,
+                    "distance": s.distance,
+                    "code": s.code,
+                    "diff": diff(path, &body, &s.code),
+                    "elsewhere": elsewhere,
+                })
+            })
+            .collect();
+        return Ok(serde_json::to_string_pretty(&suggestions)? + "\n");
+    }
+    let mut output = String::new();
+    for suggestion in &suggestions {
+        if suggestions.len() > 1 {
+            output.push_str(&format!(
+                "==> {} (distance {:.3}) <==\n",
+                suggestion.snippet, suggestion.distance
+            ));
+        }
+        if !refactor.diff {
+            output.push_str(&suggestion.code);
+            for change in &suggestion.elsewhere {
+                eprintln!(
+                    "{} also changes {}, see --diff",
+                    suggestion.snippet,
+                    relative(&change.path).display()
+                );
+            }
+            continue;
+        }
+        output.push_str(&diff(path, &body, &suggestion.code));
+        for change in &suggestion.elsewhere {
+            output.push_str(&diff(
+                &relative(&change.path),
+                &change.original,
+                &change.code,
+            ));
+        }
+    }
+    Ok(output)
+}
+
+// a unified diff of one file, with git's `a/` and `b/` prefixes
+fn diff(path: &Path, original: &str, code: &str) -> String {
+    TextDiff::from_lines(original, code)
+        .unified_diff()
+        .header(
+            &format!("a/{}", path.display()),
+            &format!("b/{}", path.display()),
+        )
+        .to_string()
+}
@@ -0,0 +1,51 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use kdl::KdlDocument;
+
+use crate::formatter::Formatter;
+use crate::language;
+use crate::trigger::Triggers;
+
+#[derive(Debug, Default)]
+pub struct Config {
+    /// Formatters with the language they apply to, as named in the config.
+    pub formatters: Vec<(String, Formatter)>,
+    pub languages: Vec<language::Configured>,
+    pub triggers: Triggers,
+}
+
+impl Config {
+    /// Reads the config at `path`, a missing file being the same as an empty one.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read_to_string(path)?;
+        let doc: KdlDocument = contents
+            .parse()
+            .with_context(|| format!("failed to parse KDL: {}", path.display()))?;
+
+        let base = path.parent().unwrap_or(Path::new("."));
+        let mut config = Self::default();
+        for node in doc.nodes() {
+            match node.name().value() {
+                "formatter" => {
+                    let Some(lang) = node.entry(0).and_then(|v| v.value().as_string()) else {
+                        bail!("formatter node must name the language it formats");
+                    };
+                    config
+                        .formatters
+                        .push((lang.to_string(), Formatter::from_node(node)?));
+                }
+                "language" => config
+                    .languages
+                    .push(language::Configured::from_node(node, base)?),
+                "triggers" => config.triggers = Triggers::from_node(node)?,
+                other => bail!("unknown config node: {other}"),
+            }
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,148 @@
+use std::ops::RangeInclusive;
+
+use anyhow::Result;
+use serde_json::{Value, json};
+use tree_sitter::{Language, Node};
+
+use crate::state::parse_into_tree;
+
+/// How much of the syntax tree to show, and how.
+#[derive(Debug, Default)]
+pub struct Options {
+    /// An indented tree with field names and positions instead of an S expression.
+    pub tree: bool,
+    /// Also show anonymous nodes, such as punctuation and keywords.
+    pub anonymous: bool,
+    /// Only the nodes lying within these 1-based lines.
+    pub lines: Option<RangeInclusive<usize>>,
+    /// Only the smallest named node around this byte offset.
+    pub byte: Option<usize>,
+    pub json: bool,
+}
+
+pub fn dump(source: &str, lang: &Language, options: &Options) -> Result<String> {
+    let tree = parse_into_tree(source.as_bytes(), lang)?;
+    let root = tree.root_node();
+
+    let nodes = match (options.byte, &options.lines) {
+        (Some(byte), _) => vec![
+            root.named_descendant_for_byte_range(byte, byte)
+                .unwrap_or(root),
+        ],
+        (None, Some(lines)) => {
+            let mut nodes = vec![];
+            within_lines(root, lines, options.anonymous, &mut nodes);
+            nodes
+        }
+        (None, None) => vec![root],
+    };
+
+    if options.json {
+        let nodes: Vec<_> = nodes
+            .iter()
+            .map(|&node| to_json(node, None, source, options.anonymous))
+            .collect();
+        return Ok(serde_json::to_string_pretty(&nodes)?);
+    }
+    let mut output = String::new();
+    for node in nodes {
+        if options.tree {
+            write_tree(node, None, 0, source, options.anonymous, &mut output);
+        } else {
+            output.push_str(&node.to_sexp());
+            output.push('\n');
+        }
+    }
+    Ok(output)
+}
+
+// the outermost nodes lying within `lines`, descending into those partly in them
+fn within_lines<'a>(
+    node: Node<'a>,
+    lines: &RangeInclusive<usize>,
+    anonymous: bool,
+    nodes: &mut Vec<Node<'a>>,
+) {
+    let first = node.start_position().row + 1;
+    // a node ending at the start of a line does not reach into it
+    let end = node.end_position();
+    let last = if end.column == 0 && end.row > node.start_position().row {
+        end.row
+    } else {
+        end.row + 1
+    };
+    if last < *lines.start() || *lines.end() < first {
+        return;
+    }
+    if lines.contains(&first) && lines.contains(&last) {
+        nodes.push(node);
+        return;
+    }
+    for (_, child) in children(node, anonymous) {
+        within_lines(child, lines, anonymous, nodes);
+    }
+}
+
+// children along with the name of the field holding them
+fn children(node: Node<'_>, anonymous: bool) -> Vec<(Option<&'static str>, Node<'_>)> {
+    (0..node.child_count())
+        .filter_map(|i| Some((node.field_name_for_child(i as u32), node.child(i)?)))
+        .filter(|(_, child)| anonymous || child.is_named())
+        .collect()
+}
+
+fn write_tree(
+    node: Node<'_>,
+    field: Option<&str>,
+    depth: usize,
+    source: &str,
+    anonymous: bool,
+    output: &mut String,
+) {
+    let start = node.start_position();
+    let end = node.end_position();
+    output.push_str(&"  ".repeat(depth));
+    if let Some(field) = field {
+        output.push_str(&format!("{field}: "));
+    }
+    if node.is_named() {
+        output.push_str(node.kind());
+    } else {
+        output.push_str(&format!("{:?}", node.kind()));
+    }
+    output.push_str(&format!(
+        " [{}:{} - {}:{}]",
+        start.row + 1,
+        start.column + 1,
+        end.row + 1,
+        end.column + 1
+    ));
+    if node.is_named() && node.child_count() == 0 {
+        output.push_str(&format!(" {:?}", &source[node.byte_range()]));
+    }
+    output.push('\n');
+    for (field, child) in children(node, anonymous) {
+        write_tree(child, field, depth + 1, source, anonymous, output);
+    }
+}
+
+// positions are tree-sitter's, rows and columns counting from 0
+fn to_json(node: Node<'_>, field: Option<&str>, source: &str, anonymous: bool) -> Value {
+    let point = |point: tree_sitter::Point, byte: usize| json!({ "row": point.row, "column": point.column, "byte": byte });
+    let mut value = json!({
+        "kind": node.kind(),
+        "named": node.is_named(),
+        "field": field,
+        "start": point(node.start_position(), node.start_byte()),
+        "end": point(node.end_position(), node.end_byte()),
+    });
+    if node.child_count() == 0 {
+        value["text"] = json!(&source[node.byte_range()]);
+    } else {
+        value["children"] = children(node, anonymous)
+            .into_iter()
+            .map(|(field, child)| to_json(child, field, source, anonymous))
+            .collect();
+    }
+    value
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail};
+use kdl::KdlNode;
+
+use crate::language;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Keys formatters by the name of the language they apply to, which the config
+/// may give as an alias. The languages must be registered first.
+pub fn by_language(named: Vec<(String, Formatter)>) -> Result<HashMap<String, Formatter>> {
+    named
+        .into_iter()
+        .map(|(name, formatter)| match language::by_name(&name) {
+            Some(language) => Ok((language.name.clone(), formatter)),
+            None => bail!("formatter for unknown language `{name}`"),
+        })
+        .collect()
+}
+
+/// An external program that reads code on stdin and writes it back formatted.
+#[derive(Debug, Clone)]
+pub struct Formatter {
+    pub program: String,
+    pub args: Vec<String>,
+    pub timeout: Duration,
+}
+
+impl Formatter {
+    pub fn from_node(node: &KdlNode) -> Result<Self> {
+        let Some(children) = node.children() else {
+            bail!("formatter node must contain a `command`");
+        };
+        let Some(command) = children.get("command") else {
+            bail!("formatter node must contain a `command`");
+        };
+        let mut words = command
+            .entries()
+            .iter()
+            .filter(|entry| entry.name().is_none())
+            .map(|entry| {
+                entry
+                    .value()
+                    .as_string()
+                    .map(|v| v.to_string())
+                    .context("formatter command arguments must be strings")
+            });
+        let Some(program) = words.next().transpose()? else {
+            bail!("formatter command must name a program");
+        };
+        let args = words.collect::<Result<_>>()?;
+
+        let timeout = match children.get_arg("timeout-ms") {
+            Some(ms) => {
+                let ms = ms
+                    .as_integer()
+                    .and_then(|ms| u64::try_from(ms).ok())
+                    .context("formatter `timeout-ms` must be a positive integer")?;
+                Duration::from_millis(ms)
+            }
+            None => DEFAULT_TIMEOUT,
+        };
+
+        Ok(Self {
+            program,
+            args,
+            timeout,
+        })
+    }
+
+    pub fn run(&self, code: &str) -> Result<String> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("failed to spawn formatter `{}`", self.program))?;
+
+        let mut stdin = child.stdin.take().context("formatter stdin unavailable")?;
+        let mut stdout = child
+            .stdout
+            .take()
+            .context("formatter stdout unavailable")?;
+        let input = code.to_string();
+        // feed and drain the pipes on their own threads so a chatty formatter
+        // can't deadlock against us while we wait on it
+        let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+        let reader = std::thread::spawn(move || {
+            let mut output = String::new();
+            stdout.read_to_string(&mut output).map(|_| output)
+        });
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                child.kill()?;
+                child.wait()?;
+                bail!(
+                    "formatter `{}` timed out after {:?}",
+                    self.program,
+                    self.timeout
+                );
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+
+        if !status.success() {
+            bail!("formatter `{}` exited with {status}", self.program);
+        }
+        // the formatter may exit without reading all of its input
+        let _ = writer.join();
+        let output = reader
+            .join()
+            .map_err(|_| anyhow::anyhow!("formatter output reader panicked"))??;
+        Ok(output)
+    }
+
+    /// Formats the code, falling back to it verbatim if the formatter fails.
+    pub fn format_or_keep(&self, code: String) -> String {
+        match self.run(&code) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                tracing::warn!("keeping unformatted output: {e:#}");
+                code
+            }
+        }
+    }
+}
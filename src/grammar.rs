@@ -0,0 +1,61 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use kdl::KdlNode;
+use tree_sitter::{LANGUAGE_VERSION, MIN_COMPATIBLE_LANGUAGE_VERSION};
+use tree_sitter_language::LanguageFn;
+
+/// A tree-sitter grammar compiled into a shared library, loaded at startup.
+#[derive(Debug)]
+pub struct Grammar {
+    pub library: PathBuf,
+    pub symbol: String,
+}
+
+impl Grammar {
+    /// Reads `grammar "path/to/lib.so" symbol="..."` for the language `name`.
+    /// Relative library paths are resolved against `base`, the directory of the config.
+    pub fn from_node(node: &KdlNode, name: &str, base: &Path) -> Result<Self> {
+        let Some(library) = node.entry(0).and_then(|v| v.value().as_string()) else {
+            bail!("grammar for `{name}` must name a library");
+        };
+        let symbol = match node.get("symbol") {
+            Some(symbol) => symbol
+                .as_string()
+                .context("grammar `symbol` must be a string")?
+                .to_string(),
+            None => format!("tree_sitter_{}", name.replace('-', "_")),
+        };
+
+        Ok(Self {
+            library: base.join(library),
+            symbol,
+        })
+    }
+
+    pub fn load(&self) -> Result<tree_sitter::Language> {
+        // SAFETY: the library is trusted to be a grammar built by the tree-sitter
+        // CLI, whose language function takes nothing and returns a `TSLanguage`.
+        let language = unsafe {
+            let library = libloading::Library::new(&self.library)
+                .with_context(|| format!("failed to load {}", self.library.display()))?;
+            let language_fn = *library
+                .get::<unsafe extern "C" fn() -> *const ()>(self.symbol.as_bytes())
+                .with_context(|| {
+                    format!("{} has no symbol `{}`", self.library.display(), self.symbol)
+                })?;
+            // the language points into the library for as long as we run
+            std::mem::forget(library);
+            tree_sitter::Language::new(LanguageFn::from_raw(language_fn))
+        };
+
+        let version = language.abi_version();
+        if !(MIN_COMPATIBLE_LANGUAGE_VERSION..=LANGUAGE_VERSION).contains(&version) {
+            bail!(
+                "grammar {} has ABI version {version}, expected {MIN_COMPATIBLE_LANGUAGE_VERSION} through {LANGUAGE_VERSION}",
+                self.library.display()
+            );
+        }
+        Ok(language)
+    }
+}
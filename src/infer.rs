@@ -0,0 +1,319 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::{Result, bail};
+use heck::ToSnakeCase;
+use tree_sitter::Node;
+
+use crate::sources::{quote, raw};
+use crate::state::{lang_from_file_extension, parse_into_tree};
+
+/// Drafts a mutation collection rewriting the code in `before` into the code in
+/// `after`, from the smallest part of their syntax trees that differs.
+///
+/// Identifiers and literals which carry over into `after` become captures, those
+/// which do not are pinned to their text. The draft is meant to be refined and
+/// checked with `silos ast dry-run`.
+pub fn infer_rule(before: &Path, after: &Path) -> Result<String> {
+    let lang = lang_from_file_extension(before)?;
+    let before_source = std::fs::read_to_string(before)?;
+    let after_source = std::fs::read_to_string(after)?;
+    let before_tree = parse_into_tree(before_source.as_bytes(), &lang)?;
+    let after_tree = parse_into_tree(after_source.as_bytes(), &lang)?;
+    for (path, tree) in [(before, &before_tree), (after, &after_tree)] {
+        if tree.root_node().has_error() {
+            tracing::warn!(
+                "{} has syntax errors, the draft may not match",
+                path.display()
+            );
+        }
+    }
+
+    let Some((before_node, after_node)) = differing(
+        before_tree.root_node(),
+        after_tree.root_node(),
+        &before_source,
+        &after_source,
+    ) else {
+        bail!(
+            "{} and {} have the same syntax tree",
+            before.display(),
+            after.display()
+        );
+    };
+    let (before_node, after_node) = with_structure(before_node, after_node);
+
+    let mut draft = Draft {
+        source: &before_source,
+        carried: HashSet::new(),
+        captures: vec![],
+        predicates: vec![],
+        names: HashSet::from(["root".to_string()]),
+    };
+    atoms(after_node, &mut |atom| {
+        if capturable(atom) {
+            draft.carried.insert(&after_source[atom.byte_range()]);
+        }
+    });
+    let pattern = draft.pattern(before_node);
+    let expression = std::iter::once(format!("{pattern} @root"))
+        .chain(draft.predicates.iter().cloned())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let mut kdl = String::from("description \"TODO: describe what this rule does\"\nmutation {\n");
+    kdl.push_str(&format!(
+        "\texpression {}\n",
+        raw(&format!("({expression})"))
+    ));
+    kdl.push_str("\tsubstitute {\n");
+    for substitute in substitutes(after_node, &after_source, &draft.captures) {
+        kdl.push_str(&format!("\t\t{substitute}\n"));
+    }
+    kdl.push_str("\t}\n}\n");
+    Ok(kdl)
+}
+
+// the innermost pair of nodes holding every difference between the two trees
+fn differing<'a>(
+    before: Node<'a>,
+    after: Node<'a>,
+    before_source: &str,
+    after_source: &str,
+) -> Option<(Node<'a>, Node<'a>)> {
+    if before_source[before.byte_range()] == after_source[after.byte_range()] {
+        return None;
+    }
+    if before.kind() != after.kind() || before.child_count() != after.child_count() {
+        return Some((before, after));
+    }
+    let mut changed = (0..before.child_count()).filter_map(|i| {
+        let (before, after) = (before.child(i)?, after.child(i)?);
+        (before_source[before.byte_range()] != after_source[after.byte_range()])
+            .then_some((before, after))
+    });
+    match (changed.next(), changed.next()) {
+        (Some((b, a)), None) => {
+            differing(b, a, before_source, after_source).or(Some((before, after)))
+        }
+        _ => Some((before, after)),
+    }
+}
+
+// a lone token makes for a rule matching far too much, so climb to a node
+// with at least two named children
+fn with_structure<'a>(mut before: Node<'a>, mut after: Node<'a>) -> (Node<'a>, Node<'a>) {
+    while before.named_child_count() < 2 {
+        let (Some(b), Some(a)) = (before.parent(), after.parent()) else {
+            break;
+        };
+        (before, after) = (b, a);
+    }
+    (before, after)
+}
+
+fn capturable(node: Node<'_>) -> bool {
+    let kind = node.kind();
+    node.is_named() && (kind.contains("identifier") || literal(kind))
+}
+
+fn literal(kind: &str) -> bool {
+    ["literal", "string", "number", "integer", "float"]
+        .iter()
+        .any(|literal| kind.contains(literal))
+}
+
+// identifiers, literals and tokens, in source order
+fn atoms<'a>(node: Node<'a>, visit: &mut impl FnMut(Node<'a>)) {
+    if node.child_count() == 0 || capturable(node) {
+        visit(node);
+        return;
+    }
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        atoms(child, visit);
+    }
+}
+
+struct Draft<'a> {
+    source: &'a str,
+    /// Text of the identifiers and literals found in the rewritten code.
+    carried: HashSet<&'a str>,
+    /// Capture names along with the text they had in the example.
+    captures: Vec<(String, String)>,
+    predicates: Vec<String>,
+    names: HashSet<String>,
+}
+
+impl Draft<'_> {
+    fn pattern(&mut self, node: Node<'_>) -> String {
+        let kind = node.kind();
+        if capturable(node) {
+            let text = &self.source[node.byte_range()];
+            let name = self.name(kind, text);
+            let same = self
+                .captures
+                .iter()
+                .find(|(_, captured)| captured == text)
+                .map(|(first, _)| first.clone());
+            match same {
+                Some(first) => self.predicates.push(format!("(#eq? @{name} @{first})")),
+                None if self.carried.contains(text) => {
+                    self.captures.push((name.clone(), text.to_string()))
+                }
+                None => self
+                    .predicates
+                    .push(format!("(#eq? @{name} {})", query_string(text))),
+            }
+            return format!("({kind}) @{name}");
+        }
+
+        let mut pattern = format!("({kind}");
+        for i in 0..node.child_count() {
+            let Some(child) = node.child(i) else {
+                continue;
+            };
+            if child.is_extra() || child.is_missing() {
+                continue;
+            }
+            pattern.push(' ');
+            if let Some(field) = node.field_name_for_child(i as u32) {
+                pattern.push_str(&format!("{field}: "));
+            }
+            // operators and keywords tell apart otherwise alike nodes, `==` from `!=`
+            if child.is_named() {
+                pattern.push_str(&self.pattern(child));
+            } else {
+                pattern.push_str(&query_string(child.kind()));
+            }
+        }
+        pattern.push(')');
+        pattern
+    }
+
+    fn name(&mut self, kind: &str, text: &str) -> String {
+        let base = if literal(kind) {
+            ["string", "number", "integer", "float"]
+                .into_iter()
+                .find(|literal| kind.contains(literal))
+                .unwrap_or("literal")
+                .to_string()
+        } else {
+            text.to_snake_case()
+        };
+        let base = if base.is_empty() || base.starts_with(|c: char| c.is_ascii_digit()) {
+            kind.to_snake_case()
+        } else {
+            base
+        };
+        let name = (1..)
+            .map(|n| match n {
+                1 => base.clone(),
+                n => format!("{base}_{n}"),
+            })
+            .find(|name| !self.names.contains(name))
+            .unwrap_or(base);
+        self.names.insert(name.clone());
+        name
+    }
+}
+
+fn query_string(text: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// the rewritten code as `literal` and `capture` nodes, literals written relative
+// to the indentation of the line the code starts on
+fn substitutes(after: Node<'_>, source: &str, captures: &[(String, String)]) -> Vec<String> {
+    let line_start = source[..after.start_byte()]
+        .rfind('\n')
+        .map_or(0, |newline| newline + 1);
+    let line = &source[line_start..];
+    let indent = &line[..line.len() - line.trim_start_matches([' ', '\t']).len()];
+    let literal = |text: &str| quote(&text.replace(&format!("\n{indent}"), "\n"));
+
+    let mut substitutes = vec![];
+    let mut position = after.start_byte();
+    atoms(after, &mut |atom| {
+        let text = &source[atom.byte_range()];
+        let Some((name, _)) = captures.iter().find(|(_, captured)| captured == text) else {
+            return;
+        };
+        if !capturable(atom) {
+            return;
+        }
+        if position < atom.start_byte() {
+            substitutes.push(format!(
+                "literal {}",
+                literal(&source[position..atom.start_byte()])
+            ));
+        }
+        substitutes.push(format!("capture {}", quote(name)));
+        position = atom.end_byte();
+    });
+    if position < after.end_byte() {
+        substitutes.push(format!(
+            "literal {}",
+            literal(&source[position..after.end_byte()])
+        ));
+    }
+    substitutes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(lang: &str, source: &str, kind: &str, carried: &[&str]) -> String {
+        let lang = crate::language::by_name(lang)
+            .and_then(|language| language.grammar.clone())
+            .unwrap();
+        let tree = parse_into_tree(source.as_bytes(), &lang).unwrap();
+        let mut cursor = tree.walk();
+        let node = loop {
+            if cursor.node().kind() == kind {
+                break cursor.node();
+            }
+            if !cursor.goto_first_child() {
+                while !cursor.goto_next_sibling() {
+                    assert!(cursor.goto_parent(), "no {kind} in {source}");
+                }
+            }
+        };
+        let mut draft = Draft {
+            source,
+            carried: carried.iter().copied().collect(),
+            captures: vec![],
+            predicates: vec![],
+            names: HashSet::new(),
+        };
+        draft.pattern(node)
+    }
+
+    #[test]
+    fn operators_are_kept_in_the_pattern() {
+        assert_eq!(
+            pattern("py", "x == None\n", "comparison_operator", &["x"]),
+            r#"(comparison_operator (identifier) @x operators: "==" (none))"#
+        );
+        assert_eq!(
+            pattern(
+                "go",
+                "package main\nvar b = a != nil\n",
+                "binary_expression",
+                &["a"]
+            ),
+            r#"(binary_expression left: (identifier) @a operator: "!=" right: (nil))"#
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result, bail};
+use kdl::KdlNode;
+
+use crate::grammar::Grammar;
+
+/// Everything silos knows about a language. The name doubles as the
+/// directory its snippets live in under `generate` and `refactor`.
+#[derive(Debug, Clone, Default)]
+pub struct Language {
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub extensions: Vec<String>,
+    pub filenames: Vec<String>,
+    pub language_ids: Vec<String>,
+    /// Other languages whose generate snippets also apply to this one.
+    pub shares: Vec<String>,
+    pub comments: CommentSyntax,
+    pub grammar: Option<tree_sitter::Language>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CommentSyntax {
+    pub line: Vec<String>,
+    pub block: Vec<(String, String)>,
+}
+
+/// A `language` node from the config, extending the built-in entry of the same name.
+#[derive(Debug)]
+pub struct Configured {
+    language: Language,
+    grammar: Option<Grammar>,
+}
+
+static LANGUAGES: OnceLock<Vec<Language>> = OnceLock::new();
+
+fn strings(v: &[&str]) -> Vec<String> {
+    v.iter().map(|s| s.to_string()).collect()
+}
+
+fn builtin() -> Vec<Language> {
+    use tree_sitter_language::LanguageFn;
+    let language = |name: &str,
+                    aliases: &[&str],
+                    extensions: &[&str],
+                    language_ids: &[&str],
+                    comments: &CommentSyntax,
+                    grammar: Option<LanguageFn>| Language {
+        name: name.to_string(),
+        aliases: strings(aliases),
+        extensions: strings(extensions),
+        language_ids: strings(language_ids),
+        comments: comments.clone(),
+        grammar: grammar.map(Into::into),
+        ..Default::default()
+    };
+    let c_like = CommentSyntax::new(&["//"], &[("/*", "*/")]);
+    let hash = CommentSyntax::new(&["#"], &[]);
+    let python = CommentSyntax::new(&["#"], &[("\"\"\"", "\"\"\""), ("'''", "'''")]);
+
+    vec![
+        language(
+            "go",
+            &["golang"],
+            &["go"],
+            &["go"],
+            &c_like,
+            Some(tree_sitter_go::LANGUAGE),
+        ),
+        language(
+            "c",
+            &[],
+            &["c", "h"],
+            &["c"],
+            &c_like,
+            Some(tree_sitter_c::LANGUAGE),
+        ),
+        language(
+            "cpp",
+            &["c++"],
+            &["cpp", "cc", "cxx", "hpp", "hh", "hxx"],
+            &["cpp"],
+            &c_like,
+            Some(tree_sitter_cpp::LANGUAGE),
+        ),
+        language(
+            "js",
+            &["javascript"],
+            &["js", "mjs", "cjs"],
+            &["javascript"],
+            &c_like,
+            Some(tree_sitter_javascript::LANGUAGE),
+        ),
+        language(
+            "jsx",
+            &["javascriptreact"],
+            &["jsx"],
+            &["javascriptreact"],
+            &c_like,
+            Some(tree_sitter_javascript::LANGUAGE),
+        )
+        .sharing(&["js"]),
+        language(
+            "ts",
+            &["typescript"],
+            &["ts", "mts", "cts"],
+            &["typescript"],
+            &c_like,
+            Some(tree_sitter_typescript::LANGUAGE_TYPESCRIPT),
+        )
+        .sharing(&["js"]),
+        language(
+            "tsx",
+            &["typescriptreact"],
+            &["tsx"],
+            &["typescriptreact"],
+            &c_like,
+            Some(tree_sitter_typescript::LANGUAGE_TSX),
+        )
+        .sharing(&["ts", "jsx", "js"]),
+        language(
+            "rs",
+            &["rust"],
+            &["rs"],
+            &["rust"],
+            &c_like,
+            Some(tree_sitter_rust::LANGUAGE),
+        ),
+        language(
+            "py",
+            &["python"],
+            &["py", "pyi"],
+            &["python"],
+            &python,
+            Some(tree_sitter_python::LANGUAGE),
+        ),
+        language(
+            "sh",
+            &["bash", "zsh", "shell"],
+            &["sh", "bash", "zsh"],
+            &["shellscript", "sh", "bash", "zsh"],
+            &hash,
+            Some(tree_sitter_bash::LANGUAGE),
+        )
+        .with_filenames(&[".bashrc", ".bash_profile", ".profile", ".zshrc"]),
+        // its crate predates `LanguageFn` and hands out the language itself
+        language("fish", &[], &["fish"], &["fish"], &hash, None)
+            .with_grammar(tree_sitter_fish::language())
+            .with_filenames(&["config.fish"]),
+        language("make", &["makefile"], &["mk"], &["makefile"], &hash, None).with_filenames(&[
+            "Makefile",
+            "makefile",
+            "GNUmakefile",
+        ]),
+    ]
+}
+
+impl CommentSyntax {
+    fn new(line: &[&str], block: &[(&str, &str)]) -> Self {
+        Self {
+            line: strings(line),
+            block: block
+                .iter()
+                .map(|(start, end)| (start.to_string(), end.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl Configured {
+    /// Relative grammar paths are resolved against `base`, the directory of the config.
+    pub fn from_node(node: &KdlNode, base: &Path) -> Result<Self> {
+        let Some(name) = node.entry(0).and_then(|v| v.value().as_string()) else {
+            bail!("language node must be named");
+        };
+        let mut language = Language {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        let mut grammar = None;
+
+        for child in node.children().map(|doc| doc.nodes()).unwrap_or_default() {
+            let key = child.name().value();
+            let mut strings = child
+                .entries()
+                .iter()
+                .filter(|e| e.name().is_none())
+                .map(|e| {
+                    e.value()
+                        .as_string()
+                        .map(|v| v.to_string())
+                        .with_context(|| format!("language `{name}`: `{key}` takes strings"))
+                });
+            match key {
+                "aliases" => language.aliases = strings.collect::<Result<_>>()?,
+                "extensions" => language.extensions = strings.collect::<Result<_>>()?,
+                "filenames" => language.filenames = strings.collect::<Result<_>>()?,
+                "language-ids" => language.language_ids = strings.collect::<Result<_>>()?,
+                "shares" => language.shares = strings.collect::<Result<_>>()?,
+                "comment" => language.comments.line = strings.collect::<Result<_>>()?,
+                "block-comment" => {
+                    let (Some(start), Some(end), None) =
+                        (strings.next(), strings.next(), strings.next())
+                    else {
+                        bail!("language `{name}`: `block-comment` takes a start and an end");
+                    };
+                    language.comments.block.push((start?, end?));
+                }
+                "grammar" => grammar = Some(Grammar::from_node(child, name, base)?),
+                other => bail!("language `{name}`: unknown setting `{other}`"),
+            }
+        }
+
+        Ok(Self { language, grammar })
+    }
+}
+
+impl Language {
+    fn sharing(mut self, shares: &[&str]) -> Self {
+        self.shares = strings(shares);
+        self
+    }
+
+    fn with_filenames(mut self, filenames: &[&str]) -> Self {
+        self.filenames = strings(filenames);
+        self
+    }
+
+    fn with_grammar(mut self, grammar: tree_sitter::Language) -> Self {
+        self.grammar = Some(grammar);
+        self
+    }
+
+    // settings given in the config win, lists are added to
+    fn extend(&mut self, configured: Language) {
+        self.aliases.extend(configured.aliases);
+        self.extensions.extend(configured.extensions);
+        self.filenames.extend(configured.filenames);
+        self.language_ids.extend(configured.language_ids);
+        self.shares.extend(configured.shares);
+        self.comments.line.extend(configured.comments.line);
+        self.comments.block.extend(configured.comments.block);
+        if configured.grammar.is_some() {
+            self.grammar = configured.grammar;
+        }
+    }
+
+    fn is_named(&self, name: &str) -> bool {
+        self.name == name || self.aliases.iter().any(|alias| alias == name)
+    }
+
+    /// This language's name followed by the languages it shares snippets with.
+    pub fn snippet_languages(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.name.as_str()).chain(self.shares.iter().map(|s| s.as_str()))
+    }
+}
+
+/// Builds the languages table from the built-in entries and those in the config,
+/// loading any grammars they name.
+pub fn register(configured: Vec<Configured>) -> Result<()> {
+    let mut languages = builtin();
+    for Configured {
+        mut language,
+        grammar,
+    } in configured
+    {
+        if let Some(grammar) = grammar {
+            language.grammar = Some(grammar.load()?);
+        }
+        match languages.iter_mut().find(|l| l.is_named(&language.name)) {
+            Some(existing) => existing.extend(language),
+            None => {
+                if language.extensions.is_empty() {
+                    language.extensions.push(language.name.clone());
+                }
+                languages.push(language)
+            }
+        }
+    }
+    if LANGUAGES.set(languages).is_err() {
+        bail!("languages were already registered");
+    }
+    Ok(())
+}
+
+fn all() -> &'static [Language] {
+    LANGUAGES.get_or_init(builtin)
+}
+
+/// Looks a language up by its name or one of its aliases.
+pub fn by_name(name: &str) -> Option<&'static Language> {
+    all().iter().find(|l| l.is_named(name))
+}
+
+/// Looks a language up by the file name, then the extension of `path`.
+pub fn by_path(path: &Path) -> Option<&'static Language> {
+    let filename = path.file_name()?.to_str()?;
+    if let Some(language) = all()
+        .iter()
+        .find(|l| l.filenames.iter().any(|f| f == filename))
+    {
+        return Some(language);
+    }
+    let extension = path.extension()?.to_str()?;
+    all()
+        .iter()
+        .find(|l| l.extensions.iter().any(|e| e == extension))
+}
+
+/// Looks a language up by the `languageId` an LSP client sent for a document.
+pub fn by_language_id(id: &str) -> Option<&'static Language> {
+    all()
+        .iter()
+        .find(|l| l.language_ids.iter().any(|i| i == id))
+}
@@ -1,40 +1,200 @@
+use anyhow::bail;
+use serde_json::{Value, json};
+use similar::{DiffTag, TextDiff};
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::{Mutex, watch};
 use tower_lsp::lsp_types::*;
-use tower_lsp::{Client, LanguageServer};
+use tower_lsp::{Client, LanguageServer, jsonrpc};
+
+use crate::language::{self, Language};
+use crate::state::{self, State, Suggestion, Workspace};
+use crate::trigger::{self, Action};
+use crate::{mutation, sources};
+
+const SAVE_SNIPPET: &str = "silos.saveSnippet";
+
+/// How far loading the model and embedding the snippets has got.
+#[derive(Debug, Clone, Default)]
+pub enum Indexing {
+    #[default]
+    Model,
+    Snippets {
+        done: usize,
+        total: usize,
+    },
+    Ready,
+    Failed(String),
+}
+
+/// The state, loaded in the background and shared by every connection.
+#[derive(Default)]
+pub struct Loading {
+    state: OnceLock<State>,
+    progress: watch::Sender<Indexing>,
+}
+
+impl Loading {
+    /// Loads the state with `load`, which reports how many snippets it has
+    /// embedded out of the total.
+    pub fn load(&self, load: impl FnOnce(&dyn Fn(usize, usize)) -> anyhow::Result<State>) {
+        let report = |done, total| {
+            self.progress
+                .send_replace(Indexing::Snippets { done, total });
+        };
+        match load(&report) {
+            Ok(state) => {
+                let _ = self.state.set(state);
+                self.progress.send_replace(Indexing::Ready);
+            }
+            Err(e) => {
+                tracing::error!("failed to load: {e:#}");
+                self.progress
+                    .send_replace(Indexing::Failed(format!("{e:#}")));
+            }
+        }
+    }
+
+    /// The state once loaded, or else why it is not there yet.
+    pub fn get(&self) -> Result<&State, String> {
+        if let Some(state) = self.state.get() {
+            return Ok(state);
+        }
+        Err(match &*self.progress.borrow() {
+            Indexing::Snippets { done, total } => {
+                format!("silos is still indexing, {done} of {total} snippets embedded")
+            }
+            Indexing::Failed(e) => format!("silos failed to load: {e}"),
+            _ => "silos is still loading the model".to_string(),
+        })
+    }
+}
 
 pub struct Backend {
     pub client: Client,
-    pub body: Arc<Mutex<HashMap<Url, String>>>,
-    pub appstate: crate::State,
+    pub body: Arc<Mutex<HashMap<Url, Document>>>,
+    pub appstate: Arc<Loading>,
+    pub triggers: trigger::Triggers,
+    /// The workspace root given by the client, where refactors look for other files.
+    pub root: OnceLock<PathBuf>,
+    /// What the client said it supports when initializing.
+    pub capabilities: OnceLock<ClientCapabilities>,
+    /// The snippets directory, where saved snippets are written.
+    pub snippets: PathBuf,
 }
 
-fn string_range_index(s: &str, r: Range) -> &str {
-    let mut newline_count = 0;
-    let mut start = None;
-    let mut end = None;
-    for (i, c) in s.chars().enumerate() {
-        if newline_count == r.start.line && start.is_none() {
-            start.replace(i + r.start.character as usize);
-        }
+/// An open document. Its text is shared, so snapshots of it are cheap.
+#[derive(Clone)]
+pub struct Document {
+    pub text: Arc<str>,
+    pub language_id: String,
+    pub version: i32,
+}
 
-        if newline_count == r.end.line && end.is_none() {
-            end.replace(i + r.end.character as usize);
+// byte offset of an LSP position, whose character counts UTF-16 code units
+fn position_offset(s: &str, position: Position) -> usize {
+    let line_start = if position.line == 0 {
+        0
+    } else {
+        match s.match_indices('\n').nth(position.line as usize - 1) {
+            Some((newline, _)) => newline + 1,
+            None => return s.len(),
         }
-        if c == '\n' {
-            newline_count += 1;
+    };
+    let mut units = 0;
+    for (i, c) in s[line_start..].char_indices() {
+        if units >= position.character as usize || c == '\n' {
+            return line_start + i;
         }
+        units += c.len_utf16();
+    }
+    s.len()
+}
+
+fn offset_position(s: &str, offset: usize) -> Position {
+    let before = &s[..offset];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    Position::new(
+        before.matches('\n').count() as u32,
+        before[line_start..].encode_utf16().count() as u32,
+    )
+}
+
+fn string_range_index(s: &str, r: Range) -> std::ops::Range<usize> {
+    position_offset(s, r.start)..position_offset(s, r.end)
+}
+
+fn index_string_range(s: &str, r: std::ops::Range<usize>) -> Range {
+    Range::new(offset_position(s, r.start), offset_position(s, r.end))
+}
+
+// the one span of `original` to replace, and what with, to turn it into
+// `changed`, or nothing when they are the same
+fn changed_span(original: &str, changed: &str) -> Option<(std::ops::Range<usize>, String)> {
+    if original == changed {
+        return None;
+    }
+    let mut prefix = original
+        .bytes()
+        .zip(changed.bytes())
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !original.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+    let mut suffix = original[prefix..]
+        .bytes()
+        .rev()
+        .zip(changed[prefix..].bytes().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !original.is_char_boundary(original.len() - suffix) {
+        suffix -= 1;
+    }
+    Some((
+        prefix..original.len() - suffix,
+        changed[prefix..changed.len() - suffix].to_string(),
+    ))
+}
+
+// one edit per run of lines that differs between `original` and `changed`
+fn line_edits(original: &str, changed: &str) -> Vec<TextEdit> {
+    let diff = TextDiff::from_lines(original, changed);
+    let mut starts = vec![0];
+    for line in diff.old_slices() {
+        starts.push(starts.last().unwrap() + line.len());
     }
-    &s[start.unwrap_or_default()..end.unwrap_or(s.len())]
+    diff.ops()
+        .iter()
+        .filter(|op| op.tag() != DiffTag::Equal)
+        .map(|op| TextEdit {
+            range: index_string_range(
+                original,
+                starts[op.old_range().start]..starts[op.old_range().end],
+            ),
+            new_text: diff.new_slices()[op.new_range()].concat(),
+        })
+        .collect()
 }
 
 #[tower_lsp::async_trait]
 impl LanguageServer for Backend {
     async fn initialize(
         &self,
-        _: InitializeParams,
+        params: InitializeParams,
     ) -> tower_lsp::jsonrpc::Result<InitializeResult> {
+        #[allow(deprecated)]
+        let root = params
+            .workspace_folders
+            .and_then(|folders| folders.into_iter().next())
+            .map(|folder| folder.uri)
+            .or(params.root_uri)
+            .and_then(|uri| uri.to_file_path().ok());
+        if let Some(root) = root {
+            let _ = self.root.set(root);
+        }
+        let _ = self.capabilities.set(params.capabilities);
         Ok(InitializeResult {
             capabilities: ServerCapabilities {
                 text_document_sync: Some(TextDocumentSyncCapability::Kind(
@@ -43,6 +203,10 @@ impl LanguageServer for Backend {
                 code_action_provider: Some(CodeActionProviderCapability::Options(
                     CodeActionOptions::default(),
                 )),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![SAVE_SNIPPET.to_string()],
+                    ..Default::default()
+                }),
                 ..Default::default()
             },
             ..Default::default()
@@ -53,6 +217,17 @@ impl LanguageServer for Backend {
         self.client
             .log_message(MessageType::INFO, "server initialized!")
             .await;
+        let progress = self
+            .capabilities
+            .get()
+            .and_then(|capabilities| capabilities.window.as_ref()?.work_done_progress)
+            .unwrap_or_default();
+        if progress && self.appstate.get().is_err() {
+            tokio::spawn(report_indexing(
+                self.client.clone(),
+                self.appstate.progress.subscribe(),
+            ));
+        }
     }
 
     async fn shutdown(&self) -> tower_lsp::jsonrpc::Result<()> {
@@ -60,18 +235,22 @@ impl LanguageServer for Backend {
     }
 
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
-        self.body
-            .lock()
-            .await
-            .insert(params.text_document.uri, params.text_document.text);
+        self.body.lock().await.insert(
+            params.text_document.uri,
+            Document {
+                text: params.text_document.text.into(),
+                language_id: params.text_document.language_id,
+                version: params.text_document.version,
+            },
+        );
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
-        if let Some(body) = params.content_changes.into_iter().next() {
-            self.body
-                .lock()
-                .await
-                .insert(params.text_document.uri, body.text);
+        if let Some(body) = params.content_changes.into_iter().next()
+            && let Some(document) = self.body.lock().await.get_mut(&params.text_document.uri)
+        {
+            document.text = body.text.into();
+            document.version = params.text_document.version;
         }
     }
 
@@ -80,39 +259,276 @@ impl LanguageServer for Backend {
         params: CodeActionParams,
     ) -> tower_lsp::jsonrpc::Result<Option<CodeActionResponse>> {
         let uri = params.text_document.uri;
-        let Some(lang) = url_extension(&uri) else {
+        // a snapshot, so edits keep coming in while silos works out its answer,
+        // its changes carrying the version they were made against
+        let Some(document) = self.body.lock().await.get(&uri).cloned() else {
+            return Ok(None);
+        };
+        let Some(language) = self.language_of(&uri, &document).await else {
+            return Ok(None);
+        };
+        let selection = string_range_index(&document.text, params.range);
+
+        let mut actions = vec![];
+        if let Some(action) = self
+            .ask_silos(&uri, language, &document, selection.clone())
+            .await
+        {
+            actions.push(CodeActionOrCommand::CodeAction(action));
+        }
+        if !selection.is_empty() {
+            let title = "save selection as silos snippet".to_string();
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: title.clone(),
+                command: Some(Command {
+                    title,
+                    command: SAVE_SNIPPET.to_string(),
+                    arguments: Some(vec![json!(uri), json!(params.range)]),
+                }),
+                ..Default::default()
+            }));
+        }
+        Ok((!actions.is_empty()).then_some(actions))
+    }
+
+    async fn execute_command(
+        &self,
+        params: ExecuteCommandParams,
+    ) -> tower_lsp::jsonrpc::Result<Option<Value>> {
+        if params.command != SAVE_SNIPPET {
+            return Err(jsonrpc::Error::invalid_params(format!(
+                "unknown command {}",
+                params.command
+            )));
+        }
+        let mut arguments = params.arguments.into_iter();
+        let (Some(Ok(uri)), Some(Ok(range))) = (
+            arguments.next().map(serde_json::from_value::<Url>),
+            arguments.next().map(serde_json::from_value::<Range>),
+        ) else {
+            return Err(jsonrpc::Error::invalid_params(
+                "expected a document and a range",
+            ));
+        };
+        if let Err(e) = self.save_snippet(uri, range).await {
+            self.client
+                .show_message(MessageType::ERROR, format!("failed to save snippet: {e}"))
+                .await;
+        }
+        Ok(None)
+    }
+}
+
+// forwards how far indexing has got as `$/progress` notifications until it is done
+async fn report_indexing(client: Client, mut indexing: watch::Receiver<Indexing>) {
+    let token = NumberOrString::String("silos/indexing".to_string());
+    let created = client
+        .send_request::<request::WorkDoneProgressCreate>(WorkDoneProgressCreateParams {
+            token: token.clone(),
+        })
+        .await;
+    if created.is_err() {
+        return;
+    }
+    let notify = |progress| {
+        client.send_notification::<notification::Progress>(ProgressParams {
+            token: token.clone(),
+            value: ProgressParamsValue::WorkDone(progress),
+        })
+    };
+    notify(WorkDoneProgress::Begin(WorkDoneProgressBegin {
+        title: "silos".to_string(),
+        message: Some("loading the model".to_string()),
+        percentage: Some(0),
+        ..Default::default()
+    }))
+    .await;
+    let mut last = 0;
+    loop {
+        let current = indexing.borrow_and_update().clone();
+        match current {
+            Indexing::Model => {}
+            Indexing::Snippets { done, total } => {
+                let percentage = (done * 100).checked_div(total).unwrap_or(100) as u32;
+                // a notification per snippet would flood the client
+                if percentage != last || done == 0 {
+                    last = percentage;
+                    notify(WorkDoneProgress::Report(WorkDoneProgressReport {
+                        message: Some(format!("embedded {done} of {total} snippets")),
+                        percentage: Some(percentage),
+                        ..Default::default()
+                    }))
+                    .await;
+                }
+            }
+            Indexing::Ready => {
+                notify(WorkDoneProgress::End(WorkDoneProgressEnd {
+                    message: Some("ready".to_string()),
+                }))
+                .await;
+                return;
+            }
+            Indexing::Failed(e) => {
+                notify(WorkDoneProgress::End(WorkDoneProgressEnd {
+                    message: Some("failed".to_string()),
+                }))
+                .await;
+                client
+                    .show_message(MessageType::ERROR, format!("silos failed to load: {e}"))
+                    .await;
+                return;
+            }
+        }
+        if indexing.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+impl Backend {
+    // `state::blocking` with the state, once it is loaded
+    async fn blocking<T: Send + 'static>(
+        &self,
+        work: impl FnOnce(&State) -> T + Send + 'static,
+    ) -> Result<T, String> {
+        let loading = self.appstate.clone();
+        state::blocking(move || loading.get().map(work)).await?
+    }
+
+    // an action that cannot run yet, shown greyed out with the reason, or as
+    // a message to clients that cannot show that
+    async fn not_ready(&self, reason: String) -> CodeAction {
+        let disabled_support = self
+            .capabilities
+            .get()
+            .and_then(|capabilities| {
+                capabilities
+                    .text_document
+                    .as_ref()?
+                    .code_action
+                    .as_ref()?
+                    .disabled_support
+            })
+            .unwrap_or_default();
+        if !disabled_support {
+            self.client
+                .show_message(MessageType::INFO, reason.clone())
+                .await;
+        }
+        CodeAction {
+            title: "ask silos".to_string(),
+            disabled: Some(CodeActionDisabled { reason }),
+            ..Default::default()
+        }
+    }
+
+    // the client's idea of the language wins over the file name
+    async fn language_of(&self, uri: &Url, document: &Document) -> Option<&'static Language> {
+        let language = language::by_language_id(&document.language_id)
+            .or_else(|| language::by_path(&uri.to_file_path().ok()?));
+        if language.is_none() {
             self.client
                 .log_message(
                     MessageType::ERROR,
-                    "unable to determine filetype, file has no extension",
+                    format!(
+                        "unable to determine the language of {uri} with language id {}",
+                        document.language_id
+                    ),
                 )
                 .await;
-            return Ok(None);
-        };
+        }
+        language
+    }
 
-        let body_locked = self.body.lock().await;
-        let Some(body) = body_locked.get(&uri) else {
-            return Ok(None);
-        };
-        let mut range = params.range;
-        let selected_text = string_range_index(body, range);
+    async fn ask_silos(
+        &self,
+        uri: &Url,
+        language: &'static Language,
+        document: &Document,
+        selection: std::ops::Range<usize>,
+    ) -> Option<CodeAction> {
+        let lang = &language.name;
 
-        let Some(comment) = ParsedAction::new(selected_text) else {
-            return Ok(None);
-        };
+        let body = &document.text;
 
-        let action_response = match comment.action {
+        // parsing is CPU bound too, and runs for most cursor moves
+        let (text, found, triggers) = (body.clone(), selection.clone(), self.triggers.clone());
+        let comment = state::blocking(move || trigger::find(language, &text, found, &triggers))
+            .await
+            .ok()??;
+        if let Err(reason) = self.appstate.get() {
+            return Some(self.not_ready(reason).await);
+        }
+        // nothing selected past the comment, so the tree decides what it is about
+        let inferred = selection.end <= comment.comment.end;
+
+        let after_comment = body[comment.comment.end..]
+            .find('\n')
+            .map_or(body.len(), |newline| comment.comment.end + newline + 1);
+
+        let mut versions = HashMap::new();
+        let (range, action_response) = match comment.action {
             Action::Generate => {
-                range.start = range.end;
-                self.appstate
-                    .generate(&lang, comment.description, 1)
-                    .map(|v| v.into_iter().map(|s| format!("{s}\n")).collect())
-                    .map_err(|e| e.to_string())
+                let at = if inferred {
+                    after_comment
+                } else {
+                    selection.end
+                };
+                // the comment may be the last line, without a newline to insert after
+                let lead = if at == body.len() && !body.is_empty() && !body.ends_with('\n') {
+                    "\n"
+                } else {
+                    ""
+                };
+                let (lang, description) = (lang.clone(), comment.description.clone());
+                let response = self
+                    .blocking(move |appstate| appstate.generate(&lang, &description, 1))
+                    .await
+                    .and_then(|response| response.map_err(|e| e.to_string()))
+                    .map(|v| {
+                        v.into_iter()
+                            .map(|s| Suggestion {
+                                code: format!("{lead}{}\n", s.code),
+                                ..s
+                            })
+                            .collect()
+                    });
+                (Some(at..at), response)
+            }
+            Action::Refactor => {
+                let target = match comment.target {
+                    Some(target) if inferred => target,
+                    // a comment heading the selection is not code to refactor
+                    _ if selection.start <= comment.comment.start
+                        && after_comment < selection.end =>
+                    {
+                        after_comment..selection.end
+                    }
+                    _ => selection,
+                };
+                // the other open documents, as of now, are only needed by refactors
+                let mut open = HashMap::new();
+                for (uri, document) in self.body.lock().await.iter() {
+                    versions.insert(uri.clone(), document.version);
+                    if let Ok(path) = uri.to_file_path() {
+                        open.insert(path, document.text.to_string());
+                    }
+                }
+                let workspace = Workspace {
+                    root: self.root.get().cloned().or(std::env::current_dir().ok()),
+                    current: uri.to_file_path().ok(),
+                    open,
+                };
+                let (lang, description) = (lang.clone(), comment.description.clone());
+                let body = body.clone();
+                let response = self
+                    .blocking(move |appstate| {
+                        appstate.refactor(&lang, &description, &body, &target, 1, &workspace)
+                    })
+                    .await
+                    .and_then(|response| response.map_err(|e| e.to_string()));
+                (None, response)
             }
-            Action::Refactor => self
-                .appstate
-                .refactor(&lang, comment.description, selected_text, 1)
-                .map_err(|e| e.to_string()),
         };
 
         let closest_matches = match action_response {
@@ -121,66 +537,218 @@ impl LanguageServer for Backend {
                 self.client
                     .log_message(MessageType::ERROR, e.to_string())
                     .await;
-                return Ok(None);
+                return None;
             }
         };
 
-        let Some(new_text) = closest_matches.into_iter().next() else {
-            return Ok(None);
+        let suggestion = closest_matches.into_iter().next()?;
+        let (range, mut new_text) = match range {
+            Some(range) => (range, suggestion.code),
+            // a refactor comes back as the whole document, only what it changed is edited
+            None => changed_span(body, &suggestion.code)?,
         };
-        let text_edit = TextEdit { range, new_text };
-        let changes: HashMap<Url, _> = [(uri, vec![text_edit])].into_iter().collect();
-        let edit = Some(WorkspaceEdit {
-            changes: Some(changes),
-            ..Default::default()
+        let mut edits = vec![];
+        match trigger::leftover_edit(
+            body,
+            &comment.comment,
+            self.triggers.leftover(comment.action),
+            &language.comments,
+            &suggestion.snippet,
+        ) {
+            // the comment went through the refactor, its text is expected to come out as is
+            Some((leftover, text))
+                if range.start <= leftover.start && leftover.end <= range.end =>
+            {
+                new_text = new_text.replacen(&body[leftover], &text, 1);
+            }
+            Some((leftover, text))
+                if leftover.end <= range.start || range.end <= leftover.start =>
+            {
+                edits.push(TextEdit {
+                    range: index_string_range(body, leftover),
+                    new_text: text,
+                });
+            }
+            _ => {}
+        }
+        edits.push(TextEdit {
+            range: index_string_range(body, range),
+            new_text,
         });
-        let actions = vec![CodeActionOrCommand::CodeAction(CodeAction {
+        let mut files = vec![(uri.clone(), Some(document.version), edits)];
+        for change in suggestion.elsewhere {
+            let Ok(uri) = Url::from_file_path(&change.path) else {
+                continue;
+            };
+            // files the editor does not have open are changed as they are on disk
+            let version = versions.get(&uri).copied();
+            files.push((uri, version, line_edits(&change.original, &change.code)));
+        }
+        let document_changes = self
+            .capabilities
+            .get()
+            .and_then(|capabilities| {
+                capabilities
+                    .workspace
+                    .as_ref()?
+                    .workspace_edit
+                    .as_ref()?
+                    .document_changes
+            })
+            .unwrap_or_default();
+        let edit = if document_changes {
+            let edits = files
+                .into_iter()
+                .map(|(uri, version, edits)| TextDocumentEdit {
+                    text_document: OptionalVersionedTextDocumentIdentifier { uri, version },
+                    edits: edits.into_iter().map(OneOf::Left).collect(),
+                })
+                .collect();
+            WorkspaceEdit {
+                document_changes: Some(DocumentChanges::Edits(edits)),
+                ..Default::default()
+            }
+        } else {
+            // plain edits, which the client can't check against the versions
+            WorkspaceEdit {
+                changes: Some(
+                    files
+                        .into_iter()
+                        .map(|(uri, _, edits)| (uri, edits))
+                        .collect(),
+                ),
+                ..Default::default()
+            }
+        };
+        Some(CodeAction {
             title: "ask silos".to_string(),
-            edit,
+            edit: Some(edit),
             ..Default::default()
-        })];
-        Ok(Some(actions))
+        })
     }
-}
 
-pub struct ParsedAction<'a> {
-    action: Action,
-    description: &'a str,
-}
+    // writes the selection out as a `generate` snippet described by the comment
+    // above it, and adds it to the index
+    async fn save_snippet(&self, uri: Url, range: Range) -> anyhow::Result<()> {
+        // copied out, so edits are not held up while the client is asked things
+        let Some(document) = self.body.lock().await.get(&uri).cloned() else {
+            bail!("{uri} is not open");
+        };
+        let Some(language) = self.language_of(&uri, &document).await else {
+            bail!("unknown language for {uri}");
+        };
+        let selection = string_range_index(&document.text, range);
+        let Some((description, comment)) =
+            trigger::heading_comment(language, &document.text, &selection, &self.triggers)
+        else {
+            self.client
+                .show_message(
+                    MessageType::WARNING,
+                    "describe the snippet in a comment above the selection",
+                )
+                .await;
+            return Ok(());
+        };
+        // a comment opening the selection is not part of the snippet
+        let start = selection.start.max(comment.end).min(selection.end);
+        let body = sources::dedent(&document.text[start..selection.end]);
 
-pub enum Action {
-    Generate,
-    Refactor,
+        let dir = self.snippets.join("generate").join(&language.name);
+        std::fs::create_dir_all(&dir)?;
+        let mut slug = sources::slug(&description);
+        if slug.is_empty() {
+            slug = "snippet".to_string();
+        }
+        let mut path = dir.join(format!("{slug}.kdl"));
+        if path.exists() {
+            let overwrite = "Overwrite".to_string();
+            let keep_both = "Keep both".to_string();
+            let choice = self
+                .client
+                .show_message_request(
+                    MessageType::WARNING,
+                    format!("a snippet named {slug} already exists"),
+                    Some(
+                        [&overwrite, &keep_both]
+                            .map(|title| MessageActionItem {
+                                title: title.clone(),
+                                properties: HashMap::new(),
+                            })
+                            .to_vec(),
+                    ),
+                )
+                .await?;
+            match choice {
+                Some(item) if item.title == overwrite => {}
+                Some(item) if item.title == keep_both => {
+                    let mut n = 2;
+                    while path.exists() {
+                        path = dir.join(format!("{slug}_{n}.kdl"));
+                        n += 1;
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+        std::fs::write(&path, sources::generate_snippet(&description, &body))?;
+        let (lang, name) = (language.name.clone(), mutation::snippet_name(&path));
+        self.blocking(move |appstate| appstate.add_snippet(&lang, &name, &description, &body))
+            .await
+            .map_err(anyhow::Error::msg)??;
+        self.client
+            .show_message(
+                MessageType::INFO,
+                format!("saved snippet {}", path.display()),
+            )
+            .await;
+        Ok(())
+    }
 }
 
-impl<'a> ParsedAction<'a> {
-    fn new(comment: &'a str) -> Option<ParsedAction<'a>> {
-        let upto_newline = match comment.rsplit_once("\n") {
-            Some((upto_newline, _discard)) => upto_newline,
-            None => comment,
-        };
-        let maybe_generate =
-            upto_newline
-                .split_once("generate: ")
-                .map(|(_discard, description)| ParsedAction {
-                    action: Action::Generate,
-                    description,
-                });
-        let maybe_refactor =
-            upto_newline
-                .split_once("refactor: ")
-                .map(|(_discard, description)| ParsedAction {
-                    action: Action::Refactor,
-                    description,
-                });
-        maybe_generate.or(maybe_refactor)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_edits_replace_only_the_changed_lines() {
+        let original = "package main\n\nfunc a() {\n\tb()\n}\n\nfunc c() {}\n";
+        let changed = "package main\n\nfunc a() {\n\tlegacyB()\n}\n\nfunc c() {}\n";
+        let edits = line_edits(original, changed);
+        assert_eq!(
+            edits,
+            vec![TextEdit {
+                range: Range::new(Position::new(3, 0), Position::new(4, 0)),
+                new_text: "\tlegacyB()\n".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn positions_count_utf16_code_units() {
+        // `é` is one unit and two bytes, `🦀` two units and four bytes
+        let text = "é🦀x\nab🦀\n";
+        assert_eq!(position_offset(text, Position::new(0, 0)), 0);
+        assert_eq!(position_offset(text, Position::new(0, 1)), 2);
+        assert_eq!(position_offset(text, Position::new(0, 3)), 6);
+        assert_eq!(position_offset(text, Position::new(1, 2)), 10);
+        assert_eq!(position_offset(text, Position::new(1, 4)), 14);
     }
-}
 
-fn url_extension(u: &Url) -> Option<String> {
-    let file_path = u.to_file_path().ok()?;
+    #[test]
+    fn positions_past_the_end_clamp() {
+        let text = "ab🦀\ncd";
+        // past the end of a line is its end, before the newline
+        assert_eq!(position_offset(text, Position::new(0, 9)), 6);
+        assert_eq!(position_offset(text, Position::new(1, 9)), text.len());
+        assert_eq!(position_offset(text, Position::new(5, 0)), text.len());
+    }
 
-    let extension = file_path.extension()?;
-    let extension = extension.to_str()?;
-    Some(extension.to_string())
+    #[test]
+    fn positions_and_offsets_agree() {
+        let text = "é🦀x\nab🦀\n\nz";
+        for (offset, _) in text.char_indices() {
+            let position = offset_position(text, offset);
+            assert_eq!(position_offset(text, position), offset, "{position:?}");
+        }
+    }
 }
@@ -1,33 +1,63 @@
-use anyhow::{Context, Error as E, Result};
+use anyhow::Result;
 use clap::Parser;
-use hora::core::{ann_index::ANNIndex, metrics::Metric::Euclidean};
-use hora::index::hnsw_idx::HNSWIndex;
-use kdl::KdlDocument;
-use state::{State, dump_expression};
+use state::State;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tower_lsp::{LspService, Server};
 
 mod args;
+mod captures;
+mod cli;
+mod config;
+mod dump;
 mod embed;
+mod formatter;
+mod grammar;
+mod infer;
+mod language;
 mod lsp;
 mod mutation;
+mod predicate;
+mod repl;
+mod serve;
+mod socket;
 mod sources;
 mod state;
+mod trigger;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let args = match args::Cli::parse().command {
+    // stdout carries the language server protocol
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+    let cli = args::Cli::parse();
+    let config = config::Config::from_path(&cli.config)?;
+    language::register(config.languages)?;
+    let formatters = formatter::by_language(config.formatters)?;
+
+    let args = match cli.command {
         args::Command::Ast(ast) => {
             match ast {
-                args::Ast::DumpExpression(source_file) => {
-                    println!("{}", dump_expression(&source_file.path)?);
+                args::Ast::DumpExpression(dump_expression) => {
+                    let options = dump::Options {
+                        tree: dump_expression.tree,
+                        anonymous: dump_expression.anonymous,
+                        lines: dump_expression.lines,
+                        byte: dump_expression.byte,
+                        json: dump_expression.json,
+                    };
+                    let input = &dump_expression.input;
+                    let (source_bytes, langfn) =
+                        state::read_source(&input.path, input.lang.as_deref())?;
+                    let source = String::from_utf8(source_bytes)?;
+                    print!("{}", dump::dump(&source, &langfn, &options)?);
                 }
                 args::Ast::ShowCaptures(show_captures) => {
-                    let source_bytes = std::fs::read(&show_captures.path)?;
-                    let langfn = state::lang_from_file_extension(&show_captures.path)?;
+                    let input = &show_captures.input;
+                    let (source_bytes, langfn) =
+                        state::read_source(&input.path, input.lang.as_deref())?;
                     let tree = state::parse_into_tree(&source_bytes, &langfn)?;
                     let root_node = tree.root_node();
                     let cooked = mutation::query(
@@ -35,100 +65,98 @@ async fn main() -> Result<()> {
                         &show_captures.expression,
                         &langfn,
                         &source_bytes,
+                        &(0..source_bytes.len()),
+                    )?;
+                    let source = String::from_utf8(source_bytes)?;
+                    print!(
+                        "{}",
+                        captures::render(&cooked, &source, show_captures.format)?
                     );
-                    println!("{:#?}", cooked);
                 }
                 args::Ast::DryRun(dry_run) => {
                     let mutation_collection = mutation::from_path(dry_run.edit_file)?;
-                    let source_bytes = std::fs::read(&dry_run.path)?;
-                    let langfn = state::lang_from_file_extension(&dry_run.path)?;
+                    let input = &dry_run.input;
+                    let (source_bytes, langfn) =
+                        state::read_source(&input.path, input.lang.as_deref())?;
                     let tree = state::parse_into_tree(&source_bytes, &langfn)?;
                     let root_node = tree.root_node();
-                    let cooked =
-                        mutation::apply(langfn, &source_bytes, root_node, &mutation_collection)?;
+                    let cooked = mutation::apply(
+                        langfn,
+                        &source_bytes,
+                        root_node,
+                        &(0..source_bytes.len()),
+                        &mutation_collection,
+                    )?;
                     println!("{cooked}");
                 }
+                args::Ast::Repl(repl) => repl::run(&repl.path)?,
+                args::Ast::InferRule(infer_rule) => {
+                    print!(
+                        "{}",
+                        infer::infer_rule(&infer_rule.before, &infer_rule.after)?
+                    );
+                }
             }
             return Ok(());
         }
         args::Command::Lsp(lsp) => lsp,
-    };
-
-    let (model_id, revision) = args.resolve_model_and_revision();
-
-    let embed = embed::Embed::new(args.gpu, &model_id, &revision)?;
-    let mut dict = HashMap::default();
-    let dimensions = embed.hidden_size;
-
-    for (language, paths) in sources::rule_files(args.snippets.join("generate"))? {
-        for path in paths {
-            let current_lang_index = dict
-                .entry(language.clone())
-                .or_insert_with(|| HNSWIndex::new(dimensions, &Default::default()));
-
-            let doc_str = std::fs::read_to_string(&path)?;
-            let doc: KdlDocument = doc_str
-                .parse()
-                .context(format!("failed to parse KDL: {}", path.display()))?;
-
-            let Some(desc) = doc.get_arg("desc").and_then(|v| v.as_string()) else {
-                continue;
-            };
-            let Some(body) = doc.get_arg("body").and_then(|v| v.as_string()) else {
-                continue;
-            };
-            current_lang_index
-                .add(&embed.embed(desc)?, body.to_string())
-                .map_err(E::msg)?;
+        args::Command::Generate(generate) => {
+            let appstate = load_state(&generate.model, formatters, &|_, _| {})?;
+            print!("{}", cli::generate(&appstate, &generate)?);
+            return Ok(());
         }
-    }
-
-    for index in dict.values_mut() {
-        index
-            .build(hora::core::metrics::Metric::Euclidean)
-            .map_err(E::msg)?;
-    }
-
-    let mut refactor_dict = HashMap::new();
-    let mut mutations_collection = vec![];
-    for (language, paths) in sources::rule_files(args.snippets.join("refactor"))? {
-        for path in paths {
-            let mutations = mutation::from_path(path)?;
-            let current_lang_index = refactor_dict
-                .entry(language.clone())
-                .or_insert_with(|| HNSWIndex::new(dimensions, &Default::default()));
-
-            current_lang_index
-                .add(
-                    &embed.embed(&mutations.description)?,
-                    mutations_collection.len(),
-                )
-                .map_err(E::msg)?;
-            mutations_collection.push(mutations);
+        args::Command::Refactor(refactor) => {
+            let appstate = load_state(&refactor.model, formatters, &|_, _| {})?;
+            print!("{}", cli::refactor(&appstate, &refactor)?);
+            return Ok(());
         }
-    }
+        args::Command::Serve(serve) => {
+            let appstate = load_state(&serve.model, formatters, &|_, _| {})?;
+            return serve::run(appstate, serve.listen, serve.model.snippets).await;
+        }
+    };
 
-    for index in refactor_dict.values_mut() {
-        index.build(Euclidean).map_err(E::msg)?;
+    if let Some(address) = &args.connect {
+        return socket::connect(address).await;
+    }
+    // the editor gets its answer to `initialize` while the model loads
+    let appstate = Arc::new(lsp::Loading::default());
+    let snippets = args.model.snippets.clone();
+    let loading = appstate.clone();
+    tokio::task::spawn_blocking(move || {
+        loading.load(|report| load_state(&args.model, formatters, report))
+    });
+    let backend = move |client| lsp::Backend {
+        client,
+        body: Arc::new(Mutex::new(HashMap::default())),
+        appstate: appstate.clone(),
+        triggers: config.triggers.clone(),
+        root: Default::default(),
+        capabilities: Default::default(),
+        snippets: snippets.clone(),
+    };
+    if let Some(address) = &args.listen {
+        return socket::listen(address, backend).await;
     }
-
-    let appstate = State::new(
-        embed,
-        state::Generate { dict },
-        state::Refactor {
-            dict: refactor_dict,
-            mutations_collection,
-        },
-    );
 
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
 
-    let (service, socket) = LspService::new(|client| lsp::Backend {
-        client,
-        body: Arc::new(Mutex::new(HashMap::default())),
-        appstate,
-    });
+    let (service, socket) = LspService::new(backend);
     Server::new(stdin, stdout, socket).serve(service).await;
     Ok(())
 }
+
+// loads the model and embeds every snippet into the search indexes, calling
+// `report` with how many snippets are done out of the total
+fn load_state(
+    model: &args::Model,
+    formatters: HashMap<String, formatter::Formatter>,
+    report: &dyn Fn(usize, usize),
+) -> Result<State> {
+    let (model_id, revision) = model.resolve_model_and_revision();
+
+    let embed = embed::Embed::new(model.gpu, &model_id, &revision)?;
+    let (generate, refactor) = state::load_snippets(&embed, &model.snippets, report)?;
+    Ok(State::new(embed, generate, refactor, formatters))
+}
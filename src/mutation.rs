@@ -1,10 +1,16 @@
 use std::collections::HashMap;
+use std::ops::Range;
 use std::path::Path;
 use tracing::debug;
-use tree_sitter::{Language, Node, Query, QueryCursor, StreamingIterator};
+use tree_sitter::{Language, Node, Point, Query, QueryCursor, StreamingIterator};
 
-use anyhow::{Result, bail};
-use kdl::KdlDocument;
+use anyhow::{Context, Result, bail};
+use globset::{Glob, GlobMatcher};
+use heck::{ToKebabCase, ToLowerCamelCase, ToShoutySnakeCase, ToSnakeCase, ToUpperCamelCase};
+use kdl::{KdlDocument, KdlNode};
+use regex::Regex;
+
+use crate::predicate;
 
 #[derive(Debug)]
 pub struct Mutation {
@@ -13,69 +19,292 @@ pub struct Mutation {
 }
 
 pub struct MutationCollection {
+    /// The file name the collection was read from, without its extension.
+    pub name: String,
     pub description: String,
     pub mutations: Vec<Mutation>,
+    pub elsewhere: Vec<Elsewhere>,
+}
+
+/// Mutations applied to every other workspace file matching `glob`, relative to
+/// the workspace root. They may use captures from the selection's first match.
+#[derive(Debug)]
+pub struct Elsewhere {
+    pub glob: GlobMatcher,
+    pub mutations: Vec<Mutation>,
 }
 
 #[derive(Debug)]
 pub enum Substitute {
     Literal(String),
-    Capture(String),
+    Capture(Capture),
+    Conditional {
+        condition: Condition,
+        then: Vec<Substitute>,
+        otherwise: Vec<Substitute>,
+    },
 }
 
-pub fn from_path<P: AsRef<Path>>(path: P) -> Result<MutationCollection> {
-    let contents = std::fs::read_to_string(path)?;
-    let doc: KdlDocument = contents.parse()?;
-    let mut mutations = vec![];
+/// Holds when the capture is present in the match and passes every test.
+#[derive(Debug)]
+pub struct Condition {
+    pub capture: String,
+    pub tests: Vec<Test>,
+}
 
-    let mut description = None;
+#[derive(Debug)]
+pub enum Test {
+    Equals(String),
+    Matches(Regex),
+    Kind(String),
+}
 
-    for node in doc.nodes() {
-        let node_name = node.name().value();
+impl Condition {
+    fn from_node(node: &KdlNode) -> Result<Self> {
+        let Some(capture) = node.get("capture").and_then(|v| v.as_string()) else {
+            bail!("`if` node must name a `capture` to test");
+        };
 
-        if node_name != "mutation" && node_name != "description" {
-            bail!(
-                "document root must only contain `mutation` or `description` nodes: got {node_name}"
-            );
+        let mut tests = vec![];
+        for entry in node.entries() {
+            let Some(key) = entry.name() else {
+                continue;
+            };
+            let key = key.value();
+            let Some(value) = entry.value().as_string() else {
+                bail!("`if` property `{key}` must be a string");
+            };
+            tests.push(match key {
+                "capture" => continue,
+                "equals" => Test::Equals(value.to_string()),
+                "matches" => Test::Matches(
+                    Regex::new(value)
+                        .with_context(|| format!("invalid regex in `matches`: {value}"))?,
+                ),
+                "kind" => Test::Kind(value.to_string()),
+                other => bail!("unknown `if` condition: {other}"),
+            });
         }
 
-        if node_name == "description" {
-            description.replace(
-                node.entry(0)
-                    .unwrap()
-                    .value()
-                    .as_string()
-                    .unwrap()
-                    .to_string(),
-            );
-            continue;
-        }
+        Ok(Self {
+            capture: capture.to_string(),
+            tests,
+        })
+    }
 
-        let node = node.children().unwrap();
-        let Some(expression) = node.get_arg("expression").and_then(|v| v.as_string()) else {
-            bail!("mutation node must contain an expression");
+    fn holds(&self, captures: &HashMap<String, Captured>) -> bool {
+        let Some(captured) = captures.get(&self.capture) else {
+            return false;
         };
-        let Some(substitute) = node.get("substitute") else {
-            bail!("mutation node must contain an substitute");
+        self.tests.iter().all(|test| match test {
+            Test::Equals(text) => captured.text == *text,
+            Test::Matches(pattern) => pattern.is_match(&captured.text),
+            Test::Kind(kind) => captured.kind == kind,
+        })
+    }
+}
+
+/// A capture to be copied into the rewrite, passed through
+/// each of its transforms in order.
+#[derive(Debug)]
+pub struct Capture {
+    pub name: String,
+    pub transforms: Vec<Transform>,
+}
+
+#[derive(Debug)]
+pub enum Transform {
+    SnakeCase,
+    CamelCase,
+    PascalCase,
+    KebabCase,
+    ScreamingSnakeCase,
+    Lowercase,
+    Uppercase,
+    Trim,
+    Quote,
+    Unquote,
+    StripPrefix(String),
+    StripSuffix(String),
+    Replace { pattern: Regex, with: String },
+}
+
+impl Capture {
+    pub fn render(&self, text: &str) -> String {
+        self.transforms
+            .iter()
+            .fold(text.to_string(), |text, transform| transform.apply(&text))
+    }
+}
+
+impl Transform {
+    fn from_node(node: &KdlNode) -> Result<Self> {
+        let string_arg = |index: usize| -> Result<String> {
+            node.entry(index)
+                .and_then(|entry| entry.value().as_string())
+                .map(|v| v.to_string())
+                .with_context(|| {
+                    format!(
+                        "transform `{}` expects a string argument at position {index}",
+                        node.name().value()
+                    )
+                })
         };
 
-        let children = substitute.children().unwrap().nodes();
-        let mut substitute = vec![];
-        for child in children {
-            let attrib = child.entry(0).unwrap().value().as_string().unwrap();
-            let substitutor = match child.name().value() {
-                "literal" => Substitute::Literal(attrib.to_string()),
-                "capture" => Substitute::Capture(attrib.to_string()),
-                _ => unreachable!(),
-            };
+        Ok(match node.name().value() {
+            "snake-case" => Self::SnakeCase,
+            "camel-case" => Self::CamelCase,
+            "pascal-case" => Self::PascalCase,
+            "kebab-case" => Self::KebabCase,
+            "screaming-snake-case" => Self::ScreamingSnakeCase,
+            "lowercase" => Self::Lowercase,
+            "uppercase" => Self::Uppercase,
+            "trim" => Self::Trim,
+            "quote" => Self::Quote,
+            "unquote" => Self::Unquote,
+            "strip-prefix" => Self::StripPrefix(string_arg(0)?),
+            "strip-suffix" => Self::StripSuffix(string_arg(0)?),
+            "replace" => {
+                let pattern = string_arg(0)?;
+                Self::Replace {
+                    pattern: Regex::new(&pattern)
+                        .with_context(|| format!("invalid regex in `replace`: {pattern}"))?,
+                    with: string_arg(1)?,
+                }
+            }
+            other => bail!("unknown capture transform: {other}"),
+        })
+    }
 
-            substitute.push(substitutor);
+    fn apply(&self, text: &str) -> String {
+        match self {
+            Self::SnakeCase => text.to_snake_case(),
+            Self::CamelCase => text.to_lower_camel_case(),
+            Self::PascalCase => text.to_upper_camel_case(),
+            Self::KebabCase => text.to_kebab_case(),
+            Self::ScreamingSnakeCase => text.to_shouty_snake_case(),
+            Self::Lowercase => text.to_lowercase(),
+            Self::Uppercase => text.to_uppercase(),
+            Self::Trim => text.trim().to_string(),
+            Self::Quote => quote(text),
+            Self::Unquote => unquote(text),
+            Self::StripPrefix(prefix) => text
+                .strip_prefix(prefix.as_str())
+                .unwrap_or(text)
+                .to_string(),
+            Self::StripSuffix(suffix) => text
+                .strip_suffix(suffix.as_str())
+                .unwrap_or(text)
+                .to_string(),
+            Self::Replace { pattern, with } => pattern.replace_all(text, with.as_str()).to_string(),
         }
+    }
+}
 
-        mutations.push(Mutation {
-            expression: expression.to_string(),
-            substitute,
-        })
+// a double quoted literal with only the escapes every C-like language shares
+fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// strips one pair of matching quotes and undoes the common backslash escapes,
+// except in backtick strings which are raw in Go
+fn unquote(text: &str) -> String {
+    if let Some(raw) = text
+        .strip_prefix('`')
+        .and_then(|text| text.strip_suffix('`'))
+    {
+        return raw.to_string();
+    }
+    let inner = ['"', '\'']
+        .iter()
+        .find_map(|&quote| text.strip_prefix(quote)?.strip_suffix(quote));
+    let Some(inner) = inner else {
+        return text.to_string();
+    };
+
+    let mut unescaped = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some('0') => unescaped.push('\0'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+pub fn snippet_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+pub fn from_path<P: AsRef<Path>>(path: P) -> Result<MutationCollection> {
+    let path = path.as_ref();
+    let contents = std::fs::read_to_string(path)?;
+    let doc: KdlDocument = contents.parse()?;
+    let mut mutations = vec![];
+    let mut elsewhere = vec![];
+
+    let mut description = None;
+
+    for node in doc.nodes() {
+        match node.name().value() {
+            "description" => {
+                description.replace(
+                    node.entry(0)
+                        .unwrap()
+                        .value()
+                        .as_string()
+                        .unwrap()
+                        .to_string(),
+                );
+            }
+            "mutation" => mutations.push(mutation_from(node)?),
+            "elsewhere" => {
+                let Some(glob) = node.entry(0).and_then(|v| v.value().as_string()) else {
+                    bail!("elsewhere node must name a glob of files");
+                };
+                let glob = Glob::new(glob)
+                    .with_context(|| format!("invalid glob `{glob}`"))?
+                    .compile_matcher();
+                let mutations = node
+                    .children()
+                    .map(|doc| doc.nodes())
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|node| match node.name().value() {
+                        "mutation" => mutation_from(node),
+                        other => bail!("elsewhere must only contain `mutation` nodes: got {other}"),
+                    })
+                    .collect::<Result<_>>()?;
+                elsewhere.push(Elsewhere { glob, mutations });
+            }
+            node_name => bail!(
+                "document root must only contain `mutation`, `elsewhere` or `description` nodes: got {node_name}"
+            ),
+        }
     }
 
     let Some(description) = description else {
@@ -83,71 +312,394 @@ pub fn from_path<P: AsRef<Path>>(path: P) -> Result<MutationCollection> {
     };
 
     Ok(MutationCollection {
+        name: snippet_name(path),
         description,
         mutations,
+        elsewhere,
+    })
+}
+
+fn mutation_from(node: &KdlNode) -> Result<Mutation> {
+    let Some(node) = node.children() else {
+        bail!("mutation node must contain a block");
+    };
+    let Some(expression) = node.get_arg("expression").and_then(|v| v.as_string()) else {
+        bail!("mutation node must contain an expression");
+    };
+    let Some(substitute) = node.get("substitute") else {
+        bail!("mutation node must contain an substitute");
+    };
+
+    let Some(children) = substitute.children() else {
+        bail!("substitute node must contain a block of substitutions");
+    };
+    let substitute = substitutes_from(children)?;
+
+    Ok(Mutation {
+        expression: expression.to_string(),
+        substitute,
     })
 }
 
+pub fn substitutes_from(doc: &KdlDocument) -> Result<Vec<Substitute>> {
+    let mut substitutes = vec![];
+    // only an `if` without an `else` of its own may take one
+    let mut awaits_else = false;
+    for child in doc.nodes() {
+        let child_name = child.name().value();
+        let block = || {
+            child
+                .children()
+                .map(substitutes_from)
+                .transpose()
+                .map(Option::unwrap_or_default)
+        };
+
+        let substitutor = match child_name {
+            "if" => Substitute::Conditional {
+                condition: Condition::from_node(child)?,
+                then: block()?,
+                otherwise: vec![],
+            },
+            "else" => {
+                let (true, Some(Substitute::Conditional { otherwise, .. })) =
+                    (awaits_else, substitutes.last_mut())
+                else {
+                    bail!("`else` must directly follow an `if`");
+                };
+                *otherwise = block()?;
+                awaits_else = false;
+                continue;
+            }
+            "literal" | "capture" => {
+                let Some(attrib) = child.entry(0).and_then(|v| v.value().as_string()) else {
+                    bail!("`{child_name}` expects a string argument");
+                };
+                if child_name == "literal" {
+                    Substitute::Literal(attrib.to_string())
+                } else {
+                    let transforms = child
+                        .children()
+                        .map(|doc| doc.nodes().iter().map(Transform::from_node).collect())
+                        .transpose()?
+                        .unwrap_or_default();
+                    Substitute::Capture(Capture {
+                        name: attrib.to_string(),
+                        transforms,
+                    })
+                }
+            }
+            other => bail!("unknown substitution: {other}"),
+        };
+
+        awaits_else = child_name == "if";
+        substitutes.push(substitutor);
+    }
+    Ok(substitutes)
+}
+
+// Builds the text replacing a match. Literals are written relative to the
+// indentation of the line the match sits on, captures keep their own internal
+// indentation relative to the line they land on.
+struct Rewrite<'a> {
+    output: String,
+    base_indent: &'a str,
+    /// String literals of the source spanning several lines, whose lines are
+    /// part of their value and never re-indented.
+    strings: &'a [Range<usize>],
+}
+
+impl Rewrite<'_> {
+    fn current_indent(&self) -> &str {
+        let Some(newline) = self.output.rfind('\n') else {
+            return self.base_indent;
+        };
+        let line = &self.output[newline + 1..];
+        &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+    }
+
+    fn push_literal(&mut self, text: &str) {
+        let base_indent = self.base_indent;
+        self.push_lines(text, "", base_indent, &[]);
+    }
+
+    // `at` is where the text starts in the source, when it is unchanged from it
+    fn push_capture(&mut self, text: &str, source_indent: &str, at: Option<usize>) {
+        let indent = self.current_indent().to_string();
+        let verbatim: Vec<_> = at
+            .map(|at| {
+                self.strings
+                    .iter()
+                    .filter(|string| at < string.end && string.start < at + text.len())
+                    .map(|string| string.start.saturating_sub(at)..string.end - at)
+                    .collect()
+            })
+            .unwrap_or_default();
+        self.push_lines(text, source_indent, &indent, &verbatim);
+    }
+
+    // lines starting inside one of the `verbatim` ranges of `text` are kept as is
+    fn push_lines(
+        &mut self,
+        text: &str,
+        strip_indent: &str,
+        indent: &str,
+        verbatim: &[Range<usize>],
+    ) {
+        let mut lines = text.split('\n');
+        if let Some(first) = lines.next() {
+            self.output.push_str(first);
+        }
+        let mut offset = 0;
+        for (previous, line) in text.split('\n').zip(lines) {
+            offset += previous.len() + 1;
+            self.output.push('\n');
+            if verbatim
+                .iter()
+                .any(|range| range.start < offset && offset < range.end)
+            {
+                self.output.push_str(line);
+                continue;
+            }
+            let line = line
+                .strip_prefix(strip_indent)
+                .unwrap_or_else(|| line.trim_start_matches([' ', '\t']));
+            if !line.is_empty() {
+                self.output.push_str(indent);
+            }
+            self.output.push_str(line);
+        }
+    }
+}
+
+// the string literals under `node` spanning several lines
+fn multiline_strings(node: Node<'_>, strings: &mut Vec<Range<usize>>) {
+    let kind = node.kind();
+    // a concatenation is made of literals, the lines between them are code
+    if kind.contains("string")
+        && !kind.starts_with("concatenated")
+        && node.start_position().row != node.end_position().row
+    {
+        strings.push(node.byte_range());
+        return;
+    }
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        multiline_strings(child, strings);
+    }
+}
+
+// the leading whitespace of the line containing `byte`
+fn line_indent(source: &str, byte: usize) -> &str {
+    let line_start = source[..byte].rfind('\n').map_or(0, |newline| newline + 1);
+    let line = &source[line_start..];
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+fn render(
+    substitutes: &[Substitute],
+    captures: &HashMap<String, Captured>,
+    selected: &HashMap<String, String>,
+    source: &str,
+    rewrite: &mut Rewrite,
+) -> Result<()> {
+    for sub in substitutes {
+        match sub {
+            Substitute::Literal(attrib) => rewrite.push_literal(attrib),
+            Substitute::Capture(capture) => {
+                if let Some(captured) = captures.get(&capture.name) {
+                    let text = capture.render(&captured.text);
+                    let at = (text == captured.text).then_some(captured.range.start);
+                    rewrite.push_capture(&text, line_indent(source, captured.range.start), at);
+                } else if let Some(text) = selected.get(&capture.name) {
+                    rewrite.push_capture(&capture.render(text), "", None);
+                } else {
+                    bail!("capture `{}` is absent from the match", capture.name);
+                }
+            }
+            Substitute::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let branch = if condition.holds(captures) {
+                    then
+                } else {
+                    otherwise
+                };
+                render(branch, captures, selected, source, rewrite)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites the matches of the mutations whose `@root` the selection touches,
+/// returning the whole source with them replaced.
 pub fn apply(
     lang: Language,
     source_bytes: &[u8],
     root_node: Node<'_>,
+    selection: &Range<usize>,
     mutations: &MutationCollection,
 ) -> Result<String, anyhow::Error> {
-    let mut split_ats = vec![];
-    let mut query_result_map = HashMap::new();
+    rewrite(
+        &lang,
+        source_bytes,
+        root_node,
+        selection,
+        &mutations.mutations,
+        &HashMap::new(),
+    )
+}
+
+/// Applies the mutations of an `elsewhere` node to a whole file, given the
+/// captures of the selection they may refer to.
+pub fn apply_elsewhere(
+    lang: &Language,
+    source_bytes: &[u8],
+    root_node: Node<'_>,
+    elsewhere: &Elsewhere,
+    selected: &HashMap<String, String>,
+) -> Result<String> {
+    rewrite(
+        lang,
+        source_bytes,
+        root_node,
+        &(0..source_bytes.len()),
+        &elsewhere.mutations,
+        selected,
+    )
+}
+
+/// The text of every capture in the first match the selection touches of each
+/// of the collection's mutations, the earlier mutations winning.
+pub fn selected_captures(
+    lang: &Language,
+    source_bytes: &[u8],
+    root_node: Node<'_>,
+    selection: &Range<usize>,
+    mutations: &MutationCollection,
+) -> Result<HashMap<String, String>> {
+    let mut selected = HashMap::new();
     for mutation in &mutations.mutations {
-        for query_result in query(root_node, mutation.expression.as_str(), &lang, source_bytes) {
+        let query_results = query_with(
+            root_node,
+            &mutation.expression,
+            lang,
+            source_bytes,
+            selection,
+            &HashMap::new(),
+        )?;
+        let Some(first) = query_results
+            .into_iter()
+            .find(|query_result| touches(query_result.start..query_result.end, selection))
+        else {
+            continue;
+        };
+        for (name, captured) in first.captures {
+            selected.entry(name).or_insert(captured.text);
+        }
+    }
+    Ok(selected)
+}
+
+fn rewrite(
+    lang: &Language,
+    source_bytes: &[u8],
+    root_node: Node<'_>,
+    selection: &Range<usize>,
+    mutations: &[Mutation],
+    selected: &HashMap<String, String>,
+) -> Result<String> {
+    let source = std::str::from_utf8(source_bytes)?;
+    let mut strings = vec![];
+    multiline_strings(root_node, &mut strings);
+    let mut rewritten = vec![];
+    for mutation in mutations {
+        let query_results = query_with(
+            root_node,
+            mutation.expression.as_str(),
+            lang,
+            source_bytes,
+            selection,
+            selected,
+        )?;
+        for query_result in query_results
+            .into_iter()
+            .filter(|query_result| touches(query_result.start..query_result.end, selection))
+        {
             debug!("mutation query expression matched: {query_result:?}");
-            split_ats.push(query_result.start);
-            split_ats.push(query_result.end);
-
-            let mut ast_rewrite = String::default();
-            for sub in &mutation.substitute {
-                ast_rewrite.push_str(match sub {
-                    Substitute::Literal(attrib) => attrib,
-                    Substitute::Capture(attrib) => &query_result.captures[attrib],
-                })
-            }
-            debug!("AST rewritten to {ast_rewrite:?}");
 
-            query_result_map.insert(query_result.start, ast_rewrite);
+            let mut ast_rewrite = Rewrite {
+                output: String::default(),
+                base_indent: line_indent(source, query_result.start),
+                strings: &strings,
+            };
+            render(
+                &mutation.substitute,
+                &query_result.captures,
+                selected,
+                source,
+                &mut ast_rewrite,
+            )?;
+            debug!("AST rewritten to {:?}", ast_rewrite.output);
+
+            rewritten.push((query_result.start..query_result.end, ast_rewrite.output));
         }
     }
-    split_ats.sort();
-    let splits = split_at_indices(source_bytes, &split_ats);
+    // a match within another is already rewritten as part of it, so only the
+    // outermost are spliced in, the earlier mutation winning a tie
+    rewritten.sort_by_key(|(range, _)| (range.start, std::cmp::Reverse(range.end)));
     let mut output = String::default();
-    for (i, split) in splits.indices.iter().zip(splits.values) {
-        let split = std::str::from_utf8(split)?;
-        output.push_str(query_result_map.get(i).map(|v| v.as_str()).unwrap_or(split));
+    let mut end = 0;
+    for (range, text) in rewritten {
+        if range.start < end {
+            continue;
+        }
+        output.push_str(&source[end..range.start]);
+        output.push_str(&text);
+        end = range.end;
     }
+    output.push_str(&source[end..]);
     Ok(output)
 }
 
+// whether a match spanning `root` is one the selection asks for: any overlap
+// counts, and an empty selection is a cursor which must sit within the match
+fn touches(root: Range<usize>, selection: &Range<usize>) -> bool {
+    if selection.is_empty() {
+        root.start <= selection.start && selection.start <= root.end
+    } else {
+        root.start < selection.end && selection.start < root.end
+    }
+}
+
+/// A capture, spanning from its first node to its last when quantified.
 #[derive(Debug)]
-pub struct QueryCooked {
-    captures: HashMap<String, String>,
-    end: usize,
-    start: usize,
+pub struct Captured {
+    pub text: String,
+    pub kind: &'static str,
+    pub range: Range<usize>,
+    /// Every node captured, in order.
+    pub nodes: Vec<CapturedNode>,
 }
 
-pub struct SplitMap<'a> {
-    values: Vec<&'a [u8]>,
-    indices: Vec<usize>,
+#[derive(Debug)]
+pub struct CapturedNode {
+    pub kind: &'static str,
+    pub range: Range<usize>,
+    pub start: Point,
+    pub end: Point,
 }
 
-fn split_at_indices<'a>(c: &'a [u8], idx: &[usize]) -> SplitMap<'a> {
-    let mut a = 0;
-    let mut values = vec![];
-    let mut indices = vec![a];
-    for &b in idx {
-        values.push(&c[a..b]);
-        a = b;
-        indices.push(a);
-    }
-    values.push(&c[a..]);
-    assert_eq!(values.len(), indices.len());
-    SplitMap { values, indices }
+#[derive(Debug)]
+pub struct QueryCooked {
+    /// Index of the pattern in the expression which matched.
+    pub pattern: usize,
+    pub captures: HashMap<String, Captured>,
+    pub end: usize,
+    pub start: usize,
 }
 
 pub fn query<'a>(
@@ -155,8 +707,21 @@ pub fn query<'a>(
     expr: &'a str,
     lang: &Language,
     source_bytes: &[u8],
-) -> Vec<QueryCooked> {
-    let query = Query::new(lang, expr).unwrap();
+    selection: &Range<usize>,
+) -> Result<Vec<QueryCooked>> {
+    query_with(node, expr, lang, source_bytes, selection, &HashMap::new())
+}
+
+fn query_with(
+    node: Node<'_>,
+    expr: &str,
+    lang: &Language,
+    source_bytes: &[u8],
+    selection: &Range<usize>,
+    selected: &HashMap<String, String>,
+) -> Result<Vec<QueryCooked>> {
+    let query = Query::new(lang, expr)?;
+    let checks = predicate::compile(&query)?;
 
     let mut qc = QueryCursor::new();
     let mut query_matches = qc.matches(&query, node, source_bytes);
@@ -173,20 +738,35 @@ pub fn query<'a>(
         if matcha.captures.is_empty() {
             continue;
         }
+        if !checks[matcha.pattern_index]
+            .iter()
+            .all(|check| check.holds(matcha, source_bytes, selection, selected))
+        {
+            continue;
+        }
         //     println!("match {:#?}", matcha.id());
 
         for (ix, name) in capture_names.iter().enumerate() {
             let nodes = matcha.nodes_for_capture_index(ix.try_into().unwrap());
             let mut start_pos = None;
             let mut end_pos = None;
+            let mut kind = None;
+            let mut captured_nodes = vec![];
             debug!("matches for {name}");
             for node in nodes {
+                kind.get_or_insert(node.kind());
                 start_pos.get_or_insert(node.start_byte());
                 end_pos.replace(node.end_byte());
+                captured_nodes.push(CapturedNode {
+                    kind: node.kind(),
+                    range: node.byte_range(),
+                    start: node.start_position(),
+                    end: node.end_position(),
+                });
                 debug!("hit {node:#?}");
             }
 
-            let (Some(start_pos), Some(end_pos)) = (start_pos, end_pos) else {
+            let (Some(start_pos), Some(end_pos), Some(kind)) = (start_pos, end_pos, kind) else {
                 continue;
             };
 
@@ -198,13 +778,208 @@ pub fn query<'a>(
             let text_bytes = &source_bytes[start_pos..end_pos];
             let text = std::str::from_utf8(text_bytes).unwrap();
             //         println!("text: {text}");
-            capture_cooked.insert(name.to_string(), text.to_string());
+            capture_cooked.insert(
+                name.to_string(),
+                Captured {
+                    text: text.to_string(),
+                    kind,
+                    range: start_pos..end_pos,
+                    nodes: captured_nodes,
+                },
+            );
         }
         cooked.push(QueryCooked {
+            pattern: matcha.pattern_index,
             start,
             end,
             captures: capture_cooked,
         })
     }
-    cooked
+    Ok(cooked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn go(source: &str, selection: Range<usize>, expression: &str, substitute: &str) -> String {
+        let lang = crate::language::by_name("go")
+            .and_then(|language| language.grammar.clone())
+            .unwrap();
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(&lang).unwrap();
+        let tree = parser.parse(source, None).unwrap();
+        let collection = MutationCollection {
+            name: "test".to_string(),
+            description: String::new(),
+            mutations: vec![Mutation {
+                expression: expression.to_string(),
+                substitute: substitutes_from(&substitute.parse().unwrap()).unwrap(),
+            }],
+            elsewhere: vec![],
+        };
+        apply(
+            lang,
+            source.as_bytes(),
+            tree.root_node(),
+            &selection,
+            &collection,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn rewrites_only_the_selected_matches() {
+        let source = "package main\n\nfunc main() {\n\tfoo(1)\n\tfoo(2)\n}\n";
+        let second = source.find("foo(2)").unwrap();
+        let rewritten = go(
+            source,
+            second..second + "foo(2)".len(),
+            "(call_expression arguments: (_) @args) @root",
+            r#"literal "bar"; capture "args""#,
+        );
+        assert_eq!(
+            rewritten,
+            "package main\n\nfunc main() {\n\tfoo(1)\n\tbar(2)\n}\n"
+        );
+    }
+
+    #[test]
+    fn a_cursor_selects_the_matches_around_it() {
+        let source = "package main\n\nfunc main() {\n\tfoo(1)\n\tfoo(2)\n}\n";
+        let cursor = source.find("(1)").unwrap();
+        let rewritten = go(
+            source,
+            cursor..cursor,
+            "(call_expression arguments: (_) @args) @root",
+            r#"literal "bar"; capture "args""#,
+        );
+        assert_eq!(
+            rewritten,
+            "package main\n\nfunc main() {\n\tbar(1)\n\tfoo(2)\n}\n"
+        );
+    }
+
+    #[test]
+    fn in_selection_tells_captures_inside_from_those_around() {
+        let source = "package main\n\nfunc main() {\n\tfoo(1)\n}\n";
+        let args = source.find("(1)").unwrap();
+        let expression = |predicate: &str| {
+            format!(
+                "((call_expression function: (identifier) @name arguments: (_) @args) @root ({predicate} @name))"
+            )
+        };
+        let substitute = r#"literal "bar"; capture "args""#;
+        let selection = args..args + "(1)".len();
+        assert_eq!(
+            go(
+                source,
+                selection.clone(),
+                &expression("#in-selection?"),
+                substitute
+            ),
+            source
+        );
+        assert_eq!(
+            go(
+                source,
+                selection,
+                &expression("#not-in-selection?"),
+                substitute
+            ),
+            "package main\n\nfunc main() {\n\tbar(1)\n}\n"
+        );
+    }
+
+    #[test]
+    fn wrapped_code_is_indented_but_not_its_strings() {
+        let source = "package main\n\nfunc main() {\n\tfoo(1,\n\t\t`a\n  b`)\n}\n";
+        let call = source.find("foo").unwrap();
+        let rewritten = go(
+            source,
+            call..call,
+            "(expression_statement) @root",
+            r#"literal "if ok {\n\t"; capture "root"; literal "\n}""#,
+        );
+        assert_eq!(
+            rewritten,
+            "package main\n\nfunc main() {\n\tif ok {\n\t\tfoo(1,\n\t\t\t`a\n  b`)\n\t}\n}\n"
+        );
+    }
+
+    fn lines(text: &str, strip_indent: &str, indent: &str, verbatim: &[Range<usize>]) -> String {
+        let mut rewrite = Rewrite {
+            output: String::new(),
+            base_indent: "",
+            strings: &[],
+        };
+        rewrite.push_lines(text, strip_indent, indent, verbatim);
+        rewrite.output
+    }
+
+    #[test]
+    fn push_lines_moves_lines_from_one_indent_to_another() {
+        assert_eq!(
+            lines("if x {\n\t\ty()\n\t}", "\t", "    ", &[]),
+            "if x {\n    \ty()\n    }"
+        );
+    }
+
+    #[test]
+    fn push_lines_trims_lines_indented_less_than_stripped() {
+        assert_eq!(lines("a\n  b\nc", "\t\t", "\t", &[]), "a\n\tb\n\tc");
+    }
+
+    #[test]
+    fn push_lines_leaves_blank_lines_empty() {
+        assert_eq!(lines("a\n\n\tb\n", "\t", "\t", &[]), "a\n\n\tb\n");
+    }
+
+    #[test]
+    fn push_lines_keeps_verbatim_lines() {
+        let text = "s := `a\n  b`\n\tc";
+        let string = text.find('`').unwrap()..text.rfind('`').unwrap() + 1;
+        assert_eq!(
+            lines(text, "\t", "\t\t", std::slice::from_ref(&string)),
+            "s := `a\n  b`\n\t\tc"
+        );
+    }
+
+    #[test]
+    fn else_follows_a_single_if() {
+        let parse = |kdl: &str| substitutes_from(&kdl.parse().unwrap());
+        assert!(parse(r#"if capture="x" { literal "a" }; else { literal "b" }"#).is_ok());
+        for kdl in [
+            r#"if capture="x" { literal "a" }; else { literal "b" }; else { literal "c" }"#,
+            r#"literal "a"; else { literal "b" }"#,
+        ] {
+            let error = parse(kdl).unwrap_err().to_string();
+            assert_eq!(error, "`else` must directly follow an `if`", "{kdl}");
+        }
+    }
+
+    #[test]
+    fn nested_matches_rewrite_the_outermost() {
+        let source = "package main\n\nfunc main() {\n\tf(f(p))\n}\n";
+        let cursor = source.find("(p)").unwrap();
+        let rewritten = go(
+            source,
+            cursor..cursor,
+            "(call_expression function: (identifier) @name arguments: (_) @args) @root",
+            r#"literal "g"; capture "args""#,
+        );
+        assert_eq!(rewritten, "package main\n\nfunc main() {\n\tg(f(p))\n}\n");
+    }
+
+    #[test]
+    fn a_match_at_the_start_is_written_once() {
+        let source = "package main\n";
+        let rewritten = go(
+            source,
+            0..source.len(),
+            "(package_clause) @root",
+            r#"literal "package other""#,
+        );
+        assert_eq!(rewritten, "package other\n");
+    }
 }
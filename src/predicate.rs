@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use anyhow::{Result, bail};
+use tree_sitter::{Node, Query, QueryMatch, QueryPredicate, QueryPredicateArg};
+
+// tree-sitter itself evaluates the text predicates (`#eq?`, `#match?`, `#any-of?`
+// and their `not-`/`any-` forms) while matching. Everything else ends up as a
+// general predicate which is ours to check.
+#[derive(Debug)]
+pub enum Predicate {
+    /// `#kind? @capture "kind" ...`
+    Kind { capture: u32, kinds: Vec<String> },
+    /// `#has-parent? @capture "kind" ...`
+    HasParent { capture: u32, kinds: Vec<String> },
+    /// `#has-ancestor? @capture "kind" ...`
+    HasAncestor { capture: u32, kinds: Vec<String> },
+    /// `#in-selection? @capture`
+    InSelection { capture: u32 },
+    /// `#same-text? @capture @capture ...`, compared with whitespace collapsed
+    SameText { captures: Vec<u32> },
+    /// `#selected? @capture "name"`, the same text as the capture `name` in the
+    /// selection, for mutations applied elsewhere
+    Selected { capture: u32, name: String },
+}
+
+/// A predicate and whether it was written with a `not-` prefix.
+#[derive(Debug)]
+pub struct Check {
+    negated: bool,
+    predicate: Predicate,
+}
+
+/// Parses the general predicates of every pattern in the query, indexed by pattern.
+pub fn compile(query: &Query) -> Result<Vec<Vec<Check>>> {
+    (0..query.pattern_count())
+        .map(|pattern| {
+            query
+                .general_predicates(pattern)
+                .iter()
+                .map(Check::new)
+                .collect()
+        })
+        .collect()
+}
+
+impl Check {
+    fn new(raw: &QueryPredicate) -> Result<Self> {
+        let (negated, operator) = match raw.operator.strip_prefix("not-") {
+            Some(operator) => (true, operator),
+            None => (false, &*raw.operator),
+        };
+
+        let mut captures = vec![];
+        let mut strings = vec![];
+        for arg in &raw.args {
+            match arg {
+                QueryPredicateArg::Capture(ix) => captures.push(*ix),
+                QueryPredicateArg::String(s) => strings.push(s.to_string()),
+            }
+        }
+
+        let single_capture = || -> Result<u32> {
+            match captures.as_slice() {
+                [capture] => Ok(*capture),
+                _ => bail!("#{} expects exactly one capture", raw.operator),
+            }
+        };
+        let kinds = || -> Result<Vec<String>> {
+            if strings.is_empty() {
+                bail!("#{} expects at least one node kind", raw.operator);
+            }
+            Ok(strings.clone())
+        };
+
+        let predicate = match operator {
+            "kind?" => Predicate::Kind {
+                capture: single_capture()?,
+                kinds: kinds()?,
+            },
+            "has-parent?" => Predicate::HasParent {
+                capture: single_capture()?,
+                kinds: kinds()?,
+            },
+            "has-ancestor?" => Predicate::HasAncestor {
+                capture: single_capture()?,
+                kinds: kinds()?,
+            },
+            "in-selection?" => Predicate::InSelection {
+                capture: single_capture()?,
+            },
+            "same-text?" => {
+                if captures.len() < 2 || !strings.is_empty() {
+                    bail!("#{} expects two or more captures", raw.operator);
+                }
+                Predicate::SameText {
+                    captures: captures.clone(),
+                }
+            }
+            "selected?" => {
+                let [name] = strings.as_slice() else {
+                    bail!(
+                        "#{} expects a capture and the name of a selected capture",
+                        raw.operator
+                    );
+                };
+                Predicate::Selected {
+                    capture: single_capture()?,
+                    name: name.clone(),
+                }
+            }
+            _ => bail!("unknown predicate: #{}", raw.operator),
+        };
+
+        Ok(Self { negated, predicate })
+    }
+
+    pub fn holds(
+        &self,
+        matcha: &QueryMatch,
+        source_bytes: &[u8],
+        selection: &Range<usize>,
+        selected: &HashMap<String, String>,
+    ) -> bool {
+        let nodes = |capture: u32| matcha.nodes_for_capture_index(capture);
+        let holds = match &self.predicate {
+            Predicate::Kind { capture, kinds } => {
+                nodes(*capture).all(|node| kinds.iter().any(|kind| kind == node.kind()))
+            }
+            Predicate::HasParent { capture, kinds } => nodes(*capture).all(|node| {
+                node.parent()
+                    .is_some_and(|parent| kinds.iter().any(|kind| kind == parent.kind()))
+            }),
+            Predicate::HasAncestor { capture, kinds } => nodes(*capture).all(|node| {
+                ancestors(node).any(|ancestor| kinds.iter().any(|kind| kind == ancestor.kind()))
+            }),
+            Predicate::InSelection { capture } => nodes(*capture).all(|node| {
+                selection.start <= node.start_byte() && node.end_byte() <= selection.end
+            }),
+            Predicate::SameText { captures } => {
+                let mut texts = captures.iter().map(|&capture| {
+                    nodes(capture)
+                        .map(|node| collapse_whitespace(&source_bytes[node.byte_range()]))
+                        .collect::<Vec<_>>()
+                });
+                let first = texts.next().unwrap_or_default();
+                texts.all(|text| text == first)
+            }
+            Predicate::Selected { capture, name } => selected.get(name).is_some_and(|text| {
+                let text = collapse_whitespace(text.as_bytes());
+                nodes(*capture)
+                    .all(|node| collapse_whitespace(&source_bytes[node.byte_range()]) == text)
+            }),
+        };
+        holds != self.negated
+    }
+}
+
+fn ancestors(node: Node<'_>) -> impl Iterator<Item = Node<'_>> {
+    std::iter::successors(node.parent(), |node| node.parent())
+}
+
+fn collapse_whitespace(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
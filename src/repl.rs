@@ -0,0 +1,308 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+
+use anyhow::Result;
+use kdl::KdlDocument;
+use tree_sitter::{Language, QueryError, QueryErrorKind, Tree};
+
+use crate::mutation::{self, Mutation, MutationCollection};
+use crate::state::{lang_from_file_extension, parse_into_tree};
+
+const HELP: &str = "\
+enter a query, it runs once its parentheses are balanced or after an empty line
+:substitute   read substitute nodes up to an empty line and apply them with the last query
+:tree         print the syntax tree of the file
+:reload       read the file again
+:help         show this message
+:quit         leave";
+
+/// The file being queried, parsed once and kept around between queries.
+struct Session<'a> {
+    path: &'a Path,
+    lang: Language,
+    source: String,
+    tree: Tree,
+    /// The last query that compiled, which substitutes are tried with.
+    query: Option<String>,
+    color: bool,
+}
+
+/// Runs an interactive query playground over the file at `path`.
+pub fn run(path: &Path) -> Result<()> {
+    let lang = lang_from_file_extension(path)?;
+    let source = std::fs::read_to_string(path)?;
+    let tree = parse_into_tree(source.as_bytes(), &lang)?;
+    let mut session = Session {
+        path,
+        lang,
+        source,
+        tree,
+        query: None,
+        color: io::stdout().is_terminal(),
+    };
+    println!("loaded {}, :help lists the commands", path.display());
+
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        prompt("> ")?;
+        let Some(line) = lines.next().transpose()? else {
+            break;
+        };
+        match line.trim() {
+            "" => {}
+            ":quit" | ":q" => break,
+            ":help" => println!("{HELP}"),
+            ":tree" => println!("{}", session.tree.root_node().to_sexp()),
+            ":reload" => match session.reload() {
+                Ok(()) => println!("reloaded {}", session.path.display()),
+                Err(e) => println!("error: {e}"),
+            },
+            ":substitute" => {
+                let block = read_block(&mut lines, None, |_| false)?;
+                session.substitute(&block);
+            }
+            command if command.starts_with(':') => println!("unknown command {command}, try :help"),
+            _ => {
+                let query = read_block(&mut lines, Some(line), balanced)?;
+                session.query(&query);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn prompt(prompt: &str) -> io::Result<()> {
+    print!("{prompt}");
+    io::stdout().flush()
+}
+
+// reads lines until `complete` holds or an empty line, starting from `first`
+fn read_block(
+    lines: &mut impl Iterator<Item = io::Result<String>>,
+    first: Option<String>,
+    complete: impl Fn(&str) -> bool,
+) -> Result<String> {
+    let mut block = first.unwrap_or_default();
+    while !complete(&block) {
+        prompt(". ")?;
+        let Some(line) = lines.next().transpose()? else {
+            break;
+        };
+        if line.trim().is_empty() {
+            break;
+        }
+        if !block.is_empty() {
+            block.push('\n');
+        }
+        block.push_str(&line);
+    }
+    Ok(block)
+}
+
+// whether every parenthesis outside of strings has been closed
+fn balanced(query: &str) -> bool {
+    let mut depth = 0i32;
+    let mut opened = false;
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in query.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '(' if !in_string => {
+                depth += 1;
+                opened = true;
+            }
+            ')' if !in_string => depth -= 1,
+            _ => {}
+        }
+    }
+    opened && depth <= 0 && !in_string
+}
+
+impl Session<'_> {
+    fn reload(&mut self) -> Result<()> {
+        self.source = std::fs::read_to_string(self.path)?;
+        self.tree = parse_into_tree(self.source.as_bytes(), &self.lang)?;
+        Ok(())
+    }
+
+    fn query(&mut self, query: &str) {
+        let source_bytes = self.source.as_bytes();
+        let cooked = match mutation::query(
+            self.tree.root_node(),
+            query,
+            &self.lang,
+            source_bytes,
+            &(0..source_bytes.len()),
+        ) {
+            Ok(cooked) => cooked,
+            Err(e) => {
+                match e.downcast_ref::<QueryError>() {
+                    Some(error) => print_query_error(query, error),
+                    None => println!("error: {e}"),
+                }
+                return;
+            }
+        };
+        self.query = Some(query.to_string());
+
+        if cooked.is_empty() {
+            println!("no matches");
+        }
+        for (i, matched) in cooked.iter().enumerate() {
+            println!("match {}", i + 1);
+            let mut captures: Vec<_> = matched.captures.iter().collect();
+            captures.sort_by_key(|(name, captured)| (captured.range.start, name.as_str()));
+            for (name, captured) in captures {
+                println!("  @{name} {} {}", self.span(&captured.range), captured.kind);
+                self.print_highlighted(&captured.range);
+            }
+        }
+    }
+
+    fn substitute(&self, block: &str) {
+        let Some(query) = &self.query else {
+            println!("run a query first, its matches are what gets substituted");
+            return;
+        };
+        let substitute = block
+            .parse::<KdlDocument>()
+            .map_err(anyhow::Error::from)
+            .and_then(|doc| mutation::substitutes_from(&doc));
+        let substitute = match substitute {
+            Ok(substitute) => substitute,
+            Err(e) => {
+                println!("error: {e}");
+                return;
+            }
+        };
+        let collection = MutationCollection {
+            name: "repl".to_string(),
+            description: String::new(),
+            mutations: vec![Mutation {
+                expression: query.clone(),
+                substitute,
+            }],
+            elsewhere: vec![],
+        };
+        let source_bytes = self.source.as_bytes();
+        match mutation::apply(
+            self.lang.clone(),
+            source_bytes,
+            self.tree.root_node(),
+            &(0..source_bytes.len()),
+            &collection,
+        ) {
+            Ok(output) => println!("{output}"),
+            Err(e) => println!("error: {e}"),
+        }
+    }
+
+    // 1-based `line:column-line:column` of a byte range
+    fn span(&self, range: &std::ops::Range<usize>) -> String {
+        let position = |byte: usize| {
+            let before = &self.source[..byte];
+            let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+            format!(
+                "{}:{}",
+                before.matches('\n').count() + 1,
+                before[line_start..].chars().count() + 1
+            )
+        };
+        format!("{}-{}", position(range.start), position(range.end))
+    }
+
+    // prints the lines a range covers with the range itself highlighted, or
+    // underlined when the output is not a terminal
+    fn print_highlighted(&self, range: &std::ops::Range<usize>) {
+        let line_start = self.source[..range.start]
+            .rfind('\n')
+            .map_or(0, |newline| newline + 1);
+        let line_end = self.source[range.end..]
+            .find('\n')
+            .map_or(self.source.len(), |newline| range.end + newline);
+        let first_line = self.source[..line_start].matches('\n').count() + 1;
+        let mut offset = line_start;
+        for (line_number, line) in (first_line..).zip(self.source[line_start..line_end].split('\n'))
+        {
+            let from = range.start.clamp(offset, offset + line.len());
+            let to = range.end.clamp(offset, offset + line.len());
+            let (before, captured, after) = (
+                &self.source[offset..from],
+                &self.source[from..to],
+                &self.source[to..offset + line.len()],
+            );
+            if self.color {
+                println!("    {line_number:>4} | {before}\x1b[1;32m{captured}\x1b[0m{after}");
+            } else {
+                println!("    {line_number:>4} | {line}");
+                if !captured.is_empty() {
+                    println!(
+                        "         | {}{}",
+                        blank(before),
+                        "^".repeat(captured.chars().count())
+                    );
+                }
+            }
+            offset += line.len() + 1;
+        }
+    }
+}
+
+// whitespace as wide as `text`, keeping its tabs so a caret below lines up
+fn blank(text: &str) -> String {
+    text.chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect()
+}
+
+fn print_query_error(query: &str, error: &QueryError) {
+    // the message of a syntax error is the offending line, which is shown below
+    match error.kind {
+        QueryErrorKind::Syntax => println!(
+            "error: invalid syntax at {}:{}",
+            error.row + 1,
+            error.column + 1
+        ),
+        _ => println!(
+            "error: {:?} `{}` at {}:{}",
+            error.kind,
+            error.message,
+            error.row + 1,
+            error.column + 1
+        ),
+    }
+    if let Some(line) = query.lines().nth(error.row) {
+        println!("  {line}");
+        println!("  {}^", blank(line.get(..error.column).unwrap_or(line)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn balanced_waits_for_every_parenthesis_to_close() {
+        assert!(balanced("(identifier) @name"));
+        assert!(balanced("((call_expression) @root (#eq? @root \"f\"))"));
+        assert!(!balanced("(call_expression"));
+        assert!(!balanced("(call_expression\n  function: (identifier)"));
+    }
+
+    #[test]
+    fn balanced_needs_a_parenthesis() {
+        assert!(!balanced(""));
+        assert!(!balanced("identifier"));
+    }
+
+    #[test]
+    fn balanced_ignores_parentheses_in_strings() {
+        assert!(!balanced("((identifier) @x (#eq? @x \")\")"));
+        assert!(balanced("((identifier) @x (#eq? @x \"(\"))"));
+        assert!(balanced("((string) @x (#eq? @x \"\\\")\"))"));
+        assert!(!balanced("((identifier) @x (#eq? @x \"a)"));
+    }
+}
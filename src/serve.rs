@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::extract::{self, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde_json::{Value, json};
+
+use crate::state::{self, State, Workspace, blocking};
+
+/// What every request shares: the model and indexes, loaded once.
+#[derive(Clone)]
+struct Server {
+    appstate: Arc<State>,
+    /// Where the snippets are read from again on reload.
+    snippets: Arc<PathBuf>,
+}
+
+/// Serves the searches of `appstate` over HTTP on `listen` until stopped.
+pub async fn run(appstate: State, listen: SocketAddr, snippets: PathBuf) -> Result<()> {
+    let server = Server {
+        appstate: Arc::new(appstate),
+        snippets: Arc::new(snippets),
+    };
+    let app = Router::new()
+        .route("/generate", post(generate))
+        .route("/refactor", post(refactor))
+        .route("/snippets", get(snippets_list))
+        .route("/reload", post(reload))
+        .with_state(server);
+    let listener = tokio::net::TcpListener::bind(listen).await?;
+    tracing::info!("serving on http://{}", listener.local_addr()?);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+/// An error sent back as `{"error": "..."}` with its status.
+struct Failure(StatusCode, String);
+
+impl IntoResponse for Failure {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<state::Error> for Failure {
+    fn from(e: state::Error) -> Self {
+        let status = match e {
+            state::Error::UnknownLang | state::Error::SnippetParsing => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Failure(status, e.to_string())
+    }
+}
+
+fn internal(e: impl Display) -> Failure {
+    Failure(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+// a string field the request body must have
+fn string(request: &Value, field: &str) -> Result<String, Failure> {
+    match request.get(field).and_then(Value::as_str) {
+        Some(value) => Ok(value.to_string()),
+        None => Err(Failure(
+            StatusCode::BAD_REQUEST,
+            format!("expected a string `{field}`"),
+        )),
+    }
+}
+
+fn top_k(request: &Value, default: usize) -> Result<usize, Failure> {
+    match request.get("top_k") {
+        None => Ok(default),
+        Some(top_k) => top_k
+            .as_u64()
+            .map(|top_k| top_k as usize)
+            .ok_or_else(|| Failure(StatusCode::BAD_REQUEST, "expected a number `top_k`".into())),
+    }
+}
+
+/// `{"lang", "prompt", "top_k"?}` to the closest `generate` snippets.
+async fn generate(
+    extract::State(server): extract::State<Server>,
+    Json(request): Json<Value>,
+) -> Result<Json<Value>, Failure> {
+    let (lang, prompt) = (string(&request, "lang")?, string(&request, "prompt")?);
+    let top_k = top_k(&request, 5)?;
+    let suggestions = blocking(move || server.appstate.generate(&lang, &prompt, top_k))
+        .await
+        .map_err(internal)??;
+    Ok(Json(
+        suggestions
+            .iter()
+            .map(|s| json!({ "snippet": s.snippet, "distance": s.distance, "code": s.code }))
+            .collect(),
+    ))
+}
+
+/// `{"lang", "prompt", "source", "top_k"?}` to the source as refactored by
+/// the closest snippets. Other files are never read, so `elsewhere` rules
+/// only change the source itself.
+async fn refactor(
+    extract::State(server): extract::State<Server>,
+    Json(request): Json<Value>,
+) -> Result<Json<Value>, Failure> {
+    let (lang, prompt) = (string(&request, "lang")?, string(&request, "prompt")?);
+    let source = string(&request, "source")?;
+    let top_k = top_k(&request, 1)?;
+    let suggestions = blocking(move || {
+        let workspace = Workspace::default();
+        server.appstate.refactor(
+            &lang,
+            &prompt,
+            &source,
+            &(0..source.len()),
+            top_k,
+            &workspace,
+        )
+    })
+    .await
+    .map_err(internal)??;
+    Ok(Json(
+        suggestions
+            .iter()
+            .map(|s| json!({ "snippet": s.snippet, "distance": s.distance, "code": s.code }))
+            .collect(),
+    ))
+}
+
+/// Every snippet indexed, narrowed down by the `kind` and `lang` parameters.
+async fn snippets_list(
+    extract::State(server): extract::State<Server>,
+    Query(filter): Query<HashMap<String, String>>,
+) -> Json<Value> {
+    let wanted = |key: &str, value: &str| filter.get(key).is_none_or(|wanted| wanted == value);
+    Json(
+        server
+            .appstate
+            .snippets()
+            .iter()
+            .filter(|listed| wanted("kind", listed.kind) && wanted("lang", &listed.lang))
+            .map(|listed| {
+                json!({
+                    "kind": listed.kind,
+                    "lang": listed.lang,
+                    "name": listed.name,
+                    "description": listed.description,
+                })
+            })
+            .collect(),
+    )
+}
+
+/// Reads the snippets from disk again and rebuilds the indexes.
+async fn reload(extract::State(server): extract::State<Server>) -> Result<Json<Value>, Failure> {
+    let count = blocking(move || {
+        server.appstate.reload(&server.snippets)?;
+        anyhow::Ok(server.appstate.snippets().len())
+    })
+    .await
+    .map_err(internal)?
+    .map_err(internal)?;
+    Ok(Json(json!({ "snippets": count })))
+}
@@ -0,0 +1,140 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{Context, Result, bail};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tower_lsp::{Client, LspService, Server};
+
+use crate::lsp::Backend;
+
+/// Where a shared language server listens: a TCP address such as
+/// `127.0.0.1:9257`, or else the path of a Unix socket.
+#[derive(Debug, Clone)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = Infallible;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        Ok(match address.parse() {
+            Ok(address) => Address::Tcp(address),
+            Err(_) => Address::Unix(PathBuf::from(address)),
+        })
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Tcp(address) => address.fmt(f),
+            Address::Unix(path) => path.display().fmt(f),
+        }
+    }
+}
+
+/// Serves every editor connecting to `address` with a backend of its own,
+/// made by `backend`, until stopped.
+pub async fn listen<F>(address: &Address, backend: F) -> Result<()>
+where
+    F: Fn(Client) -> Backend + Clone + Send + 'static,
+{
+    match address {
+        Address::Tcp(address) => {
+            let listener = tokio::net::TcpListener::bind(address).await?;
+            tracing::info!("listening on {}", listener.local_addr()?);
+            loop {
+                let (stream, peer) = listener.accept().await?;
+                tracing::info!("editor connected from {peer}");
+                tokio::spawn(serve(stream, backend.clone()));
+            }
+        }
+        #[cfg(unix)]
+        Address::Unix(path) => {
+            use tokio::net::{UnixListener, UnixStream};
+            // a socket file nobody answers on is left over from an earlier run
+            if UnixStream::connect(path).await.is_ok() {
+                bail!("a server is already listening on {}", path.display());
+            }
+            let _ = std::fs::remove_file(path);
+            let listener = UnixListener::bind(path)?;
+            tracing::info!("listening on {}", path.display());
+            loop {
+                let (stream, _) = listener.accept().await?;
+                tracing::info!("editor connected");
+                tokio::spawn(serve(stream, backend.clone()));
+            }
+        }
+        #[cfg(not(unix))]
+        Address::Unix(path) => bail!(
+            "{} is not an IP address and port, and Unix sockets are not supported here",
+            path.display()
+        ),
+    }
+}
+
+async fn serve<S, F>(stream: S, backend: F)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    F: Fn(Client) -> Backend,
+{
+    let (read, write) = tokio::io::split(stream);
+    let (service, socket) = LspService::new(backend);
+    Server::new(read, write, socket).serve(service).await;
+}
+
+/// Relays stdio to the server listening on `address`, so an editor talks to
+/// it as if it had spawned the server itself.
+pub async fn connect(address: &Address) -> Result<()> {
+    let hint = || format!("failed to connect, is `silos lsp --listen {address}` running?");
+    match address {
+        Address::Tcp(address) => {
+            relay(
+                tokio::net::TcpStream::connect(address)
+                    .await
+                    .with_context(hint)?,
+            )
+            .await
+        }
+        #[cfg(unix)]
+        Address::Unix(path) => {
+            relay(
+                tokio::net::UnixStream::connect(path)
+                    .await
+                    .with_context(hint)?,
+            )
+            .await
+        }
+        #[cfg(not(unix))]
+        Address::Unix(path) => bail!(
+            "{} is not an IP address and port, and Unix sockets are not supported here",
+            path.display()
+        ),
+    }
+}
+
+async fn relay<S: AsyncRead + AsyncWrite>(stream: S) -> Result<()> {
+    let (mut read, mut write) = tokio::io::split(stream);
+    let (mut stdin, mut stdout) = (tokio::io::stdin(), tokio::io::stdout());
+    let upstream = async {
+        tokio::io::copy(&mut stdin, &mut write).await?;
+        write.shutdown().await
+    };
+    let downstream = tokio::io::copy(&mut read, &mut stdout);
+    tokio::pin!(downstream);
+    tokio::select! {
+        // the server hung up
+        copied = &mut downstream => {
+            copied?;
+            return Ok(());
+        }
+        sent = upstream => sent?,
+    }
+    // the editor is done, let the server finish answering
+    downstream.await?;
+    Ok(())
+}
@@ -14,21 +14,201 @@ pub fn rule_files<P: AsRef<Path>>(path: P) -> io::Result<HashMap<String, Vec<Pat
     let mut basename_to_paths = HashMap::new();
 
     for language_dir in per_language_dirs {
-        let Some(dirname) = language_dir
-            .file_stem()
-            .and_then(|v| v.to_str())
-            .map(|v| v.to_string())
-        else {
+        let Some(dirname) = language_dir.file_stem().and_then(|v| v.to_str()) else {
             continue;
         };
+        // directories named after an alias count towards the language itself
+        let language = crate::language::by_name(dirname)
+            .map(|language| language.name.clone())
+            .unwrap_or_else(|| dirname.to_string());
         let rule_file_paths: Vec<_> = fs::read_dir(&language_dir)?
             .filter_map(|res| res.ok())
             .map(|entry| entry.path())
             .filter(|file| file.is_file() && file.extension().is_some_and(|ext| ext == "kdl"))
             .map(|path| path.to_path_buf())
             .collect();
-        basename_to_paths.insert(dirname, rule_file_paths);
+        basename_to_paths
+            .entry(language)
+            .or_insert_with(Vec::new)
+            .extend(rule_file_paths);
     }
     Ok(basename_to_paths)
 }
-// fn prebuilt_index();
+
+/// A file name for a snippet, made of the first few words of its description.
+pub fn slug(description: &str) -> String {
+    use heck::ToSnakeCase;
+    let words: Vec<_> = description.split_whitespace().take(6).collect();
+    words.join(" ").to_snake_case()
+}
+
+/// Writes a `generate` snippet as KDL. The body goes into a raw string where
+/// it can, so it reads as it was written.
+pub fn generate_snippet(description: &str, body: &str) -> String {
+    format!("desc {}\nbody {}\n", quote(description), raw(body))
+}
+
+/// `text` as a KDL raw string, multi-line when it spans several lines. Text
+/// a raw string can't hold as it is comes out as a quoted string instead.
+pub fn raw(text: &str) -> String {
+    // multi-line strings empty lines of only whitespace, and `#"""#` reads as
+    // the start of a multi-line string
+    let blank = |line: &str| !line.is_empty() && line.trim().is_empty();
+    if text == "\""
+        || text.chars().any(escaped)
+        || (text.contains('\n') && text.split('\n').any(blank))
+    {
+        return quote(text);
+    }
+    // enough hashes that the text cannot end the string early
+    let hashes = (1..)
+        .map(|n| "#".repeat(n))
+        .find(|hashes| !text.contains(&format!("\"{hashes}")))
+        .unwrap_or_default();
+    if text.contains('\n') {
+        format!("{hashes}\"\"\"\n{text}\n\"\"\"{hashes}")
+    } else {
+        format!("{hashes}\"{text}\"{hashes}")
+    }
+}
+
+/// `text` as a quoted KDL string.
+pub fn quote(text: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if escaped(c) => quoted.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// characters KDL only takes escaped: newlines other than `\n`, and the code
+// points it disallows anywhere in a document
+fn escaped(c: char) -> bool {
+    c != '\n'
+        && c != '\t'
+        && (c.is_control()
+            || matches!(
+                c,
+                '\u{200e}' | '\u{200f}' | '\u{2028}'..='\u{202e}' | '\u{2066}'..='\u{2069}' | '\u{feff}'
+            ))
+}
+
+/// Strips the indentation shared by every non-blank line, and blank lines around.
+pub fn dedent(text: &str) -> String {
+    let lines: Vec<_> = text.lines().map(str::trim_end).collect();
+    let indent = lines
+        .iter()
+        .filter(|line| !line.is_empty())
+        .map(|line| line.len() - line.trim_start_matches([' ', '\t']).len())
+        .min()
+        .unwrap_or_default();
+    let lines: Vec<_> = lines
+        .iter()
+        .map(|line| line.get(indent..).unwrap_or_default())
+        .skip_while(|line| line.is_empty())
+        .collect();
+    let end = lines
+        .iter()
+        .rposition(|line| !line.is_empty())
+        .map_or(0, |i| i + 1);
+    lines[..end].join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the description and body `generate_snippet` wrote, as KDL reads them back
+    fn read_back(snippet: &str) -> (String, String) {
+        let doc: kdl::KdlDocument = snippet.parse().unwrap();
+        let string = |name| doc.get_arg(name).unwrap().as_string().unwrap().to_string();
+        (string("desc"), string("body"))
+    }
+
+    fn round_trips(description: &str, body: &str) {
+        let snippet = generate_snippet(description, body);
+        assert_eq!(
+            read_back(&snippet),
+            (description.to_string(), body.to_string()),
+            "{snippet}"
+        );
+    }
+
+    #[test]
+    fn bodies_round_trip() {
+        for body in [
+            "fmt.Println(x)",
+            "s := \"#\"",
+            "s := \"#\"\nt := \"##\"",
+            "r#\"raw\"#",
+            "\"",
+            "\"\"\"",
+            "x = \"\"\"\ndocstring\n\"\"\"",
+            "\"\"\"\nx\n\"\"\"",
+            "path := \"C:\\\\dir\"\nre := `\\d+`",
+            "ends with a backslash \\",
+            "\"quoted\"",
+            "  indented\n\tfirst",
+            "blank\n\nlines\n",
+        ] {
+            round_trips("a snippet", body);
+        }
+    }
+
+    #[test]
+    fn control_characters_round_trip() {
+        for text in [
+            "bell \u{7}",
+            "cr\rlf",
+            "tab\tnul\0",
+            "del \u{7f}",
+            "nel \u{85} ls \u{2028}",
+        ] {
+            round_trips(text, text);
+            round_trips("multi-line", &format!("first\n{text}"));
+        }
+    }
+
+    #[test]
+    fn whitespace_only_lines_round_trip() {
+        round_trips("keeps them", "a\n  \nb");
+    }
+
+    #[test]
+    fn descriptions_are_quoted() {
+        assert_eq!(quote("say \"hi\"\n"), r#""say \"hi\"\n""#);
+        round_trips("a \\ b \"c\" #d", "x");
+    }
+
+    #[test]
+    fn raw_strings_outnumber_the_hashes_inside() {
+        assert_eq!(raw("x"), "#\"x\"#");
+        assert_eq!(raw("\"#\""), "##\"\"#\"\"##");
+    }
+
+    #[test]
+    fn slugs_take_the_first_words() {
+        assert_eq!(
+            slug("Read a file into a String, then parse it"),
+            "read_a_file_into_a_string"
+        );
+    }
+
+    #[test]
+    fn dedent_strips_shared_indentation_and_blank_edges() {
+        assert_eq!(
+            dedent("\n    if x {\n        y()\n    }\n\n"),
+            "if x {\n    y()\n}"
+        );
+        assert_eq!(dedent("\ta\n\n\t\tb"), "a\n\n\tb");
+    }
+}
@@ -1,10 +1,21 @@
+use crate::embed::Embed;
+use crate::formatter::Formatter;
+use crate::language;
 use crate::mutation;
+use crate::sources;
+use anyhow::Context;
 use derive_more::Display;
 use derive_more::Error;
+use globset::GlobMatcher;
 use hora::core::ann_index::ANNIndex;
+use hora::core::metrics::Metric::Euclidean;
 use hora::index::hnsw_idx::HNSWIndex;
+use kdl::KdlDocument;
 use std::collections::HashMap;
-use std::path::Path;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
 use tree_sitter::Parser;
 
 #[derive(Debug, Display, Error)]
@@ -15,11 +26,213 @@ pub enum Error {
     UnknownLang,
     #[display("failed to parse corpus of code to apply mutation on")]
     SnippetParsing,
+    #[display("failed to build the snippet index")]
+    IndexBuild,
+}
+
+/// Marks work as no longer wanted once dropped.
+struct Cancel(Arc<AtomicBool>);
+
+impl Drop for Cancel {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Runs embedding and mutations on the blocking pool, off the async workers.
+/// A caller which stops waiting, as on a cancelled LSP request or a closed
+/// HTTP connection, drops the future awaiting this, and work which has not
+/// started by then is skipped.
+pub async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, String> {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let _cancel = Cancel(cancelled.clone());
+    tokio::task::spawn_blocking(move || {
+        if cancelled.load(Ordering::Relaxed) {
+            return Err("cancelled".to_string());
+        }
+        Ok(work())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Code produced from a snippet, along with the name of that snippet.
+#[derive(Debug, Clone)]
+pub struct Suggestion {
+    pub snippet: String,
+    /// How far the snippet's description is from the prompt, closest first.
+    pub distance: f32,
+    pub code: String,
+    pub elsewhere: Vec<FileChange>,
+}
+
+/// Another file a refactor changed, with its whole text before and after.
+#[derive(Debug, Clone)]
+pub struct FileChange {
+    pub path: PathBuf,
+    pub original: String,
+    pub code: String,
+}
+
+/// Where refactors find the other files they may change.
+#[derive(Debug, Default)]
+pub struct Workspace {
+    pub root: Option<PathBuf>,
+    /// The file being refactored, never changed as one of the others.
+    pub current: Option<PathBuf>,
+    /// Documents open in the editor, whose text wins over what is on disk.
+    pub open: HashMap<PathBuf, String>,
+}
+
+impl Workspace {
+    /// Every file other than the current one whose path relative to the root
+    /// matches `glob`, along with its text.
+    pub fn files(&self, glob: &GlobMatcher) -> Vec<(PathBuf, String)> {
+        let relative = |path: &Path| {
+            self.root
+                .as_deref()
+                .and_then(|root| path.strip_prefix(root).ok())
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|| path.to_path_buf())
+        };
+        let wanted =
+            |path: &Path| self.current.as_deref() != Some(path) && glob.is_match(relative(path));
+
+        let mut files: Vec<_> = self
+            .open
+            .iter()
+            .filter(|(path, _)| wanted(path))
+            .map(|(path, text)| (path.clone(), text.clone()))
+            .collect();
+        let Some(root) = &self.root else {
+            return files;
+        };
+        for entry in ignore::WalkBuilder::new(root).build().flatten() {
+            let path = entry.path();
+            if !entry.file_type().is_some_and(|t| t.is_file())
+                || self.open.contains_key(path)
+                || !wanted(path)
+            {
+                continue;
+            }
+            match std::fs::read_to_string(path) {
+                Ok(text) => files.push((path.to_path_buf(), text)),
+                Err(e) => tracing::warn!("skipping {}: {e}", path.display()),
+            }
+        }
+        files
+    }
+}
+
+/// A `generate` snippet, named after its file.
+pub struct Snippet {
+    pub name: String,
+    pub lang: String,
+    pub description: String,
+    pub body: String,
+    /// The embedding of the snippet's description.
+    pub embedding: Vec<f32>,
 }
 
 pub struct Refactor {
     pub dict: HashMap<String, HNSWIndex<f32, usize>>,
     pub mutations_collection: Vec<mutation::MutationCollection>,
+    /// The language of each collection, by position.
+    pub langs: Vec<String>,
+}
+
+/// A snippet as listed to clients, without its body or embedding.
+#[derive(Debug, Clone)]
+pub struct Listed {
+    /// Either `generate` or `refactor`.
+    pub kind: &'static str,
+    pub lang: String,
+    pub name: String,
+    pub description: String,
+}
+
+/// Reads every snippet under `dir` and embeds their descriptions into the
+/// `generate` and `refactor` indexes, calling `report` with how many of the
+/// snippets are done out of the total as it goes.
+pub fn load_snippets(
+    embed: &Embed,
+    dir: &Path,
+    report: &dyn Fn(usize, usize),
+) -> anyhow::Result<(Generate, Refactor)> {
+    let generate_files = sources::rule_files(dir.join("generate"))?;
+    let refactor_files = sources::rule_files(dir.join("refactor"))?;
+    let total = generate_files
+        .values()
+        .chain(refactor_files.values())
+        .map(Vec::len)
+        .sum();
+    let mut done = 0;
+    report(done, total);
+
+    let mut snippets = vec![];
+    for (language, paths) in generate_files {
+        for path in paths {
+            done += 1;
+            let doc_str = std::fs::read_to_string(&path)?;
+            let doc: KdlDocument = doc_str
+                .parse()
+                .context(format!("failed to parse KDL: {}", path.display()))?;
+
+            let Some(desc) = doc.get_arg("desc").and_then(|v| v.as_string()) else {
+                continue;
+            };
+            let Some(body) = doc.get_arg("body").and_then(|v| v.as_string()) else {
+                continue;
+            };
+            snippets.push(Snippet {
+                name: mutation::snippet_name(&path),
+                lang: language.clone(),
+                description: desc.to_string(),
+                body: body.to_string(),
+                embedding: embed.embed(desc)?,
+            });
+            report(done, total);
+        }
+    }
+    let generate = Generate::new(snippets)?;
+
+    let mut refactor_dict = HashMap::new();
+    let mut mutations_collection = vec![];
+    let mut langs = vec![];
+    for (language, paths) in refactor_files {
+        for path in paths {
+            done += 1;
+            let mutations = mutation::from_path(path)?;
+            let current_lang_index = refactor_dict
+                .entry(language.clone())
+                .or_insert_with(|| HNSWIndex::new(embed.hidden_size, &Default::default()));
+
+            current_lang_index
+                .add(
+                    &embed.embed(&mutations.description)?,
+                    mutations_collection.len(),
+                )
+                .map_err(anyhow::Error::msg)?;
+            mutations_collection.push(mutations);
+            langs.push(language.clone());
+            report(done, total);
+        }
+    }
+
+    for index in refactor_dict.values_mut() {
+        index.build(Euclidean).map_err(anyhow::Error::msg)?;
+    }
+
+    Ok((
+        generate,
+        Refactor {
+            dict: refactor_dict,
+            mutations_collection,
+            langs,
+        },
+    ))
 }
 
 impl Refactor {
@@ -28,26 +241,52 @@ impl Refactor {
         lang: &str,
         target: &[f32],
         body: &str,
+        selection: &Range<usize>,
         top_k: usize,
-    ) -> Result<Vec<String>, Error> {
+        workspace: &Workspace,
+    ) -> Result<Vec<Suggestion>, Error> {
         let langfn = lang_from_name(lang)?;
         let source_bytes = body.as_bytes();
         let tree = parse_into_tree(source_bytes, &langfn)?;
         let root_node = tree.root_node();
 
+        let Some(rules_for_lang) = self.dict.get(lang) else {
+            return Err(Error::UnknownLang);
+        };
+
         // search for k nearest neighbors
-        let collected = self.dict[lang]
-            .search(target, top_k)
-            .iter()
-            .filter_map(|&index| {
+        let collected = rules_for_lang
+            .search_nodes(target, top_k)
+            .into_iter()
+            .filter_map(|(node, distance)| Some(((*node.idx())?, distance)))
+            .filter_map(|(index, distance)| {
+                let collection = &self.mutations_collection[index];
                 let applied = mutation::apply(
                     langfn.clone(),
                     source_bytes,
                     root_node,
-                    &self.mutations_collection[index],
+                    selection,
+                    collection,
                 );
-                match applied {
-                    Ok(v) => Some(v),
+                let elsewhere = if collection.elsewhere.is_empty() {
+                    Ok(vec![])
+                } else {
+                    mutation::selected_captures(
+                        &langfn,
+                        source_bytes,
+                        root_node,
+                        selection,
+                        collection,
+                    )
+                    .map(|selected| apply_elsewhere(collection, &selected, workspace))
+                };
+                match applied.and_then(|code| Ok((code, elsewhere?))) {
+                    Ok((code, elsewhere)) => Some(Suggestion {
+                        snippet: collection.name.clone(),
+                        distance,
+                        code,
+                        elsewhere,
+                    }),
                     Err(e) => {
                         tracing::error!(
                             collection_index = index,
@@ -63,24 +302,100 @@ impl Refactor {
     }
 }
 
-pub fn lang_from_name(s: &str) -> Result<tree_sitter::Language, Error> {
-    Ok(match s {
-        "go" => tree_sitter_go::LANGUAGE,
-        "c" | "h" => tree_sitter_c::LANGUAGE,
-        "cpp" | "hpp" => tree_sitter_cpp::LANGUAGE,
-        "js" | "ts" => tree_sitter_javascript::LANGUAGE,
-        "rs" => tree_sitter_rust::LANGUAGE,
-        _ => return Err(Error::UnknownLang),
+// runs the `elsewhere` mutations of `collection` over the workspace files they
+// match, keeping only files that changed
+fn apply_elsewhere(
+    collection: &mutation::MutationCollection,
+    selected: &HashMap<String, String>,
+    workspace: &Workspace,
+) -> Vec<FileChange> {
+    let mut changed: Vec<FileChange> = vec![];
+    for elsewhere in &collection.elsewhere {
+        for (path, text) in workspace.files(&elsewhere.glob) {
+            // a file matched by several globs gets each set of mutations in turn
+            let position = changed.iter().position(|change| change.path == path);
+            let text = position.map_or(text, |i| changed[i].code.clone());
+            let Ok(langfn) = lang_from_file_extension(&path) else {
+                continue;
+            };
+            let Ok(tree) = parse_into_tree(text.as_bytes(), &langfn) else {
+                continue;
+            };
+            let code = match mutation::apply_elsewhere(
+                &langfn,
+                text.as_bytes(),
+                tree.root_node(),
+                elsewhere,
+                selected,
+            ) {
+                Ok(code) => code,
+                Err(e) => {
+                    tracing::error!("failed to apply mutations to {}: {e}", path.display());
+                    continue;
+                }
+            };
+            match position {
+                Some(i) => changed[i].code = code,
+                None if code != text => changed.push(FileChange {
+                    path,
+                    original: text,
+                    code,
+                }),
+                None => {}
+            }
+        }
     }
-    .into())
+    changed
+}
+
+pub fn lang_from_name(s: &str) -> Result<tree_sitter::Language, Error> {
+    language::by_name(s)
+        .and_then(|language| language.grammar.clone())
+        .ok_or(Error::UnknownLang)
 }
 
 pub fn lang_from_file_extension(path: &Path) -> Result<tree_sitter::Language, Error> {
-    let Some(lang) = path.extension() else {
-        return Err(Error::UnknownLang);
+    language::by_path(path)
+        .and_then(|language| language.grammar.clone())
+        .ok_or(Error::UnknownLang)
+}
+
+/// The language named `lang`, or else the one the file name of `path` points
+/// to, `-` standing for stdin which has none.
+pub fn language_for(
+    path: &Path,
+    lang: Option<&str>,
+) -> anyhow::Result<&'static language::Language> {
+    match lang {
+        Some(name) => {
+            language::by_name(name).ok_or_else(|| anyhow::anyhow!("unknown language `{name}`"))
+        }
+        None if path == Path::new("-") => anyhow::bail!("reading from stdin needs --lang"),
+        None => Ok(language::by_path(path).ok_or(Error::UnknownLang)?),
+    }
+}
+
+/// Reads the file at `path`, or stdin when it is `-`.
+pub fn read_path(path: &Path) -> std::io::Result<Vec<u8>> {
+    if path != Path::new("-") {
+        return std::fs::read(path);
+    }
+    let mut source = vec![];
+    std::io::Read::read_to_end(&mut std::io::stdin(), &mut source)?;
+    Ok(source)
+}
+
+/// Reads the source at `path`, `-` meaning stdin, along with the grammar of
+/// the language named `lang`, or else the one its file name points to.
+pub fn read_source(
+    path: &Path,
+    lang: Option<&str>,
+) -> anyhow::Result<(Vec<u8>, tree_sitter::Language)> {
+    let language = language_for(path, lang)?;
+    let Some(grammar) = language.grammar.clone() else {
+        anyhow::bail!("language `{}` has no grammar", language.name);
     };
-    let lang = lang.to_str().ok_or(Error::UnknownLang)?;
-    lang_from_name(lang)
+    Ok((read_path(path)?, grammar))
 }
 
 // parses `body` written in the language `langfn` into tree sitter AST
@@ -96,60 +411,241 @@ pub fn parse_into_tree(
     Ok(tree)
 }
 
-pub fn dump_expression(path: &Path) -> Result<String, Error> {
-    let source_bytes = std::fs::read(path).map_err(|_| Error::SnippetParsing)?;
-
-    let tree = parse_into_tree(&source_bytes, &lang_from_file_extension(path)?)?;
-
-    Ok(tree.root_node().to_sexp().to_string())
-}
-
 pub struct Generate {
-    pub dict: HashMap<String, HNSWIndex<f32, String>>,
+    /// Indexes of positions in `snippets`, keyed by language.
+    dict: HashMap<String, HNSWIndex<f32, usize>>,
+    snippets: Vec<Snippet>,
 }
 
 impl Generate {
-    fn search(&self, lang: &str, target: &[f32], top_k: usize) -> Result<Vec<String>, Error> {
-        let Some(snippets_for_lang) = self.dict.get(lang) else {
+    pub fn new(snippets: Vec<Snippet>) -> Result<Self, Error> {
+        let mut langs: Vec<_> = snippets.iter().map(|s| s.lang.clone()).collect();
+        langs.sort();
+        langs.dedup();
+        let mut generate = Self {
+            dict: HashMap::new(),
+            snippets,
+        };
+        for lang in langs {
+            generate.reindex(&lang)?;
+        }
+        Ok(generate)
+    }
+
+    /// Adds a snippet, or replaces the one of the same name, and rebuilds
+    /// the index of its language.
+    pub fn add(&mut self, snippet: Snippet) -> Result<(), Error> {
+        let lang = snippet.lang.clone();
+        match self
+            .snippets
+            .iter_mut()
+            .find(|s| s.lang == snippet.lang && s.name == snippet.name)
+        {
+            Some(existing) => *existing = snippet,
+            None => self.snippets.push(snippet),
+        }
+        self.reindex(&lang)
+    }
+
+    fn reindex(&mut self, lang: &str) -> Result<(), Error> {
+        let mut snippets = self
+            .snippets
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.lang == lang)
+            .peekable();
+        let Some((_, first)) = snippets.peek() else {
+            self.dict.remove(lang);
+            return Ok(());
+        };
+        let mut index = HNSWIndex::new(first.embedding.len(), &Default::default());
+        for (i, snippet) in snippets {
+            index
+                .add(&snippet.embedding, i)
+                .map_err(|_| Error::IndexBuild)?;
+        }
+        index.build(Euclidean).map_err(|_| Error::IndexBuild)?;
+        self.dict.insert(lang.to_string(), index);
+        Ok(())
+    }
+
+    fn search(&self, lang: &str, target: &[f32], top_k: usize) -> Result<Vec<Suggestion>, Error> {
+        let Some(language) = language::by_name(lang) else {
             return Err(Error::UnknownLang);
         };
-        Ok(snippets_for_lang.search(target, top_k))
+        let indexes: Vec<_> = language
+            .snippet_languages()
+            .filter_map(|lang| self.dict.get(lang))
+            .collect();
+        if indexes.is_empty() {
+            return Err(Error::UnknownLang);
+        }
+
+        // merge the nearest neighbors from every index by distance
+        let mut scored: Vec<_> = indexes
+            .into_iter()
+            .flat_map(|index| index.search_nodes(target, top_k))
+            .filter_map(|(node, distance)| Some((distance, (*node.idx())?)))
+            .collect();
+        scored.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        Ok(scored
+            .into_iter()
+            .take(top_k)
+            .map(|(distance, index)| {
+                let snippet = &self.snippets[index];
+                Suggestion {
+                    snippet: snippet.name.clone(),
+                    distance,
+                    code: snippet.body.clone(),
+                    elsewhere: vec![],
+                }
+            })
+            .collect())
     }
 }
 
 pub struct State {
-    embed: crate::embed::Embed,
-    generate: Generate,
-    refactor: Refactor,
+    embed: Embed,
+    generate: RwLock<Generate>,
+    refactor: RwLock<Refactor>,
+    formatters: HashMap<String, Formatter>,
 }
 
 impl State {
-    pub fn new(embed: crate::embed::Embed, generate: Generate, refactor: Refactor) -> Self {
+    pub fn new(
+        embed: Embed,
+        generate: Generate,
+        refactor: Refactor,
+        formatters: HashMap<String, Formatter>,
+    ) -> Self {
         Self {
             embed,
-            generate,
-            refactor,
+            generate: RwLock::new(generate),
+            refactor: RwLock::new(refactor),
+            formatters,
         }
     }
-    pub fn generate(&self, lang: &str, prompt: &str, top_k: usize) -> Result<Vec<String>, Error> {
+
+    /// Reads the snippets under `dir` again and swaps in the new indexes,
+    /// searches carrying on with the old ones while they are embedded.
+    pub fn reload(&self, dir: &Path) -> anyhow::Result<()> {
+        let (generate, refactor) = load_snippets(&self.embed, dir, &|_, _| {})?;
+        *self
+            .generate
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = generate;
+        *self
+            .refactor
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = refactor;
+        Ok(())
+    }
+
+    /// Every snippet indexed, `generate` ones first.
+    pub fn snippets(&self) -> Vec<Listed> {
+        let generate = self.generate.read().unwrap_or_else(PoisonError::into_inner);
+        let refactor = self.refactor.read().unwrap_or_else(PoisonError::into_inner);
+        let generated = generate.snippets.iter().map(|snippet| Listed {
+            kind: "generate",
+            lang: snippet.lang.clone(),
+            name: snippet.name.clone(),
+            description: snippet.description.clone(),
+        });
+        let refactors = refactor
+            .mutations_collection
+            .iter()
+            .zip(&refactor.langs)
+            .map(|(collection, lang)| Listed {
+                kind: "refactor",
+                lang: lang.clone(),
+                name: collection.name.clone(),
+                description: collection.description.clone(),
+            });
+        generated.chain(refactors).collect()
+    }
+    pub fn generate(
+        &self,
+        lang: &str,
+        prompt: &str,
+        top_k: usize,
+    ) -> Result<Vec<Suggestion>, Error> {
         let Ok(target) = self.embed.embed(prompt) else {
             return Err(Error::EmbedFailed);
         };
 
-        self.generate.search(lang, &target, top_k)
+        self.generate
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .search(lang, &target, top_k)
     }
 
+    /// Embeds `description` and adds the snippet to the live `generate` index.
+    pub fn add_snippet(
+        &self,
+        lang: &str,
+        name: &str,
+        description: &str,
+        body: &str,
+    ) -> Result<(), Error> {
+        let Ok(embedding) = self.embed.embed(description) else {
+            return Err(Error::EmbedFailed);
+        };
+        self.generate
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .add(Snippet {
+                name: name.to_string(),
+                lang: lang.to_string(),
+                description: description.to_string(),
+                body: body.to_string(),
+                embedding,
+            })
+    }
+
+    /// Refactors the matches in `body` which `selection` touches with the
+    /// snippets closest to the prompt, each suggestion being the whole body.
     pub fn refactor(
         &self,
         lang: &str,
         prompt: &str,
         body: &str,
+        selection: &Range<usize>,
         top_k: usize,
-    ) -> Result<Vec<String>, Error> {
+        workspace: &Workspace,
+    ) -> Result<Vec<Suggestion>, Error> {
         let Ok(target) = self.embed.embed(prompt) else {
             return Err(Error::EmbedFailed);
         };
 
-        self.refactor.search(lang, &target, body, top_k)
+        let refactored = self
+            .refactor
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .search(lang, &target, body, selection, top_k, workspace)?;
+        Ok(refactored
+            .into_iter()
+            .map(|suggestion| Suggestion {
+                code: self.format(lang, suggestion.code),
+                elsewhere: suggestion
+                    .elsewhere
+                    .into_iter()
+                    .map(|change| FileChange {
+                        code: match language::by_path(&change.path) {
+                            Some(language) => self.format(&language.name, change.code),
+                            None => change.code,
+                        },
+                        ..change
+                    })
+                    .collect(),
+                ..suggestion
+            })
+            .collect())
+    }
+
+    fn format(&self, lang: &str, code: String) -> String {
+        match language::by_name(lang).and_then(|language| self.formatters.get(&language.name)) {
+            Some(formatter) => formatter.format_or_keep(code),
+            None => code,
+        }
     }
 }
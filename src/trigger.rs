@@ -0,0 +1,405 @@
+use std::ops::Range;
+
+use anyhow::{Context, Result, bail};
+use kdl::KdlNode;
+use tree_sitter::Node;
+
+use crate::language::{CommentSyntax, Language};
+use crate::state::parse_into_tree;
+
+/// The prefixes which, at the start of a comment, ask silos to act.
+#[derive(Debug, Clone)]
+pub struct Triggers {
+    pub generate: Vec<String>,
+    pub refactor: Vec<String>,
+    /// What a refactor applies to when nothing past the comment is selected.
+    pub scope: Scope,
+    /// What becomes of the trigger comment, unless the action says otherwise.
+    pub comment: Leftover,
+    pub generate_comment: Option<Leftover>,
+    pub refactor_comment: Option<Leftover>,
+}
+
+/// What becomes of the trigger comment once its action is applied.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Leftover {
+    #[default]
+    Keep,
+    Strip,
+    /// Replaced by a comment naming the snippet used.
+    Audit,
+}
+
+impl Leftover {
+    fn from_value(value: Option<&str>) -> Result<Self> {
+        Ok(match value {
+            Some("keep") => Self::Keep,
+            Some("strip") => Self::Strip,
+            Some("audit") => Self::Audit,
+            _ => bail!("trigger comment must be one of keep, strip or audit"),
+        })
+    }
+}
+
+/// The code a trigger comment refers to when only the comment is selected.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Scope {
+    /// The statement or item following the comment, else the block it sits in.
+    #[default]
+    NextStatement,
+    /// The function the comment sits in, else the next statement.
+    EnclosingFunction,
+    File,
+}
+
+impl Default for Triggers {
+    fn default() -> Self {
+        Self {
+            generate: vec!["generate:".to_string()],
+            refactor: vec!["refactor:".to_string()],
+            scope: Scope::default(),
+            comment: Leftover::default(),
+            generate_comment: None,
+            refactor_comment: None,
+        }
+    }
+}
+
+impl Triggers {
+    pub fn from_node(node: &KdlNode) -> Result<Self> {
+        let mut triggers = Self::default();
+        for child in node.children().map(|doc| doc.nodes()).unwrap_or_default() {
+            if child.name().value() == "scope" {
+                triggers.scope = match child.entry(0).and_then(|v| v.value().as_string()) {
+                    Some("next-statement") => Scope::NextStatement,
+                    Some("enclosing-function") => Scope::EnclosingFunction,
+                    Some("file") => Scope::File,
+                    _ => bail!(
+                        "trigger scope must be one of next-statement, enclosing-function or file"
+                    ),
+                };
+                continue;
+            }
+            if child.name().value() == "comment" {
+                triggers.comment =
+                    Leftover::from_value(child.entry(0).and_then(|v| v.value().as_string()))?;
+                continue;
+            }
+            let leftover = child
+                .get("comment")
+                .map(|v| Leftover::from_value(v.as_string()))
+                .transpose()?;
+            let prefixes = child
+                .entries()
+                .iter()
+                .filter(|entry| entry.name().is_none())
+                .map(|entry| {
+                    entry
+                        .value()
+                        .as_string()
+                        .map(|v| v.to_string())
+                        .context("trigger prefixes must be strings")
+                })
+                .collect::<Result<_>>()?;
+            match child.name().value() {
+                "generate" => {
+                    triggers.generate = prefixes;
+                    triggers.generate_comment = leftover;
+                }
+                "refactor" => {
+                    triggers.refactor = prefixes;
+                    triggers.refactor_comment = leftover;
+                }
+                other => bail!("unknown trigger: {other}"),
+            }
+        }
+        Ok(triggers)
+    }
+
+    pub fn leftover(&self, action: Action) -> Leftover {
+        match action {
+            Action::Generate => self.generate_comment,
+            Action::Refactor => self.refactor_comment,
+        }
+        .unwrap_or(self.comment)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    Generate,
+    Refactor,
+}
+
+#[derive(Debug)]
+pub struct ParsedAction {
+    pub action: Action,
+    pub description: String,
+    /// Byte range of the comment holding the trigger.
+    pub comment: Range<usize>,
+    /// Byte range of the code the comment refers to, when there is a tree to tell.
+    pub target: Option<Range<usize>>,
+}
+
+/// Finds the first comment overlapping `selection` that starts with a trigger.
+pub fn find(
+    language: &Language,
+    source: &str,
+    selection: Range<usize>,
+    triggers: &Triggers,
+) -> Option<ParsedAction> {
+    let Some(grammar) = &language.grammar else {
+        // without a grammar, the first selected line has to be the comment
+        let line_end = source[selection.clone()]
+            .find('\n')
+            .map_or(selection.end, |newline| selection.start + newline);
+        let line = source[selection.start..line_end].trim_start();
+        let syntax = &language.comments;
+        let has_syntax = !syntax.line.is_empty() || !syntax.block.is_empty();
+        let commented = syntax
+            .line
+            .iter()
+            .any(|token| line.starts_with(token.as_str()))
+            || syntax
+                .block
+                .iter()
+                .any(|(start, _)| line.starts_with(start.as_str()));
+        if has_syntax && !commented {
+            return None;
+        }
+        return parse_comment(
+            source,
+            selection.start..line_end,
+            &language.comments,
+            triggers,
+        );
+    };
+
+    let tree = parse_into_tree(source.as_bytes(), grammar).ok()?;
+    let mut comments = vec![];
+    collect_comments(tree.root_node(), &selection, &mut comments);
+    comments.into_iter().find_map(|comment| {
+        let mut parsed = parse_comment(source, comment.byte_range(), &language.comments, triggers)?;
+        parsed.target = target(comment, triggers.scope).map(|node| node.byte_range());
+        Some(parsed)
+    })
+}
+
+fn target(comment: Node<'_>, scope: Scope) -> Option<Node<'_>> {
+    // a docstring stands for the statement wrapping it
+    let comment = match comment.parent() {
+        Some(parent) if !comment.kind().contains("comment") => parent,
+        _ => comment,
+    };
+    let ancestors = || std::iter::successors(comment.parent(), |node| node.parent());
+    let next_statement = || {
+        std::iter::successors(comment.next_named_sibling(), |node| {
+            node.next_named_sibling()
+        })
+        .find(|node| !is_comment(*node))
+        .or_else(|| comment.parent().filter(|parent| parent.parent().is_some()))
+    };
+    match scope {
+        Scope::NextStatement => next_statement(),
+        Scope::EnclosingFunction => ancestors()
+            .find(|node| node.kind().contains("function") || node.kind().contains("method"))
+            .or_else(next_statement),
+        Scope::File => ancestors().last(),
+    }
+}
+
+/// The edit turning the trigger `comment` into what `leftover` asks for, if any.
+/// A comment alone on its line is stripped along with the line.
+pub fn leftover_edit(
+    source: &str,
+    comment: &Range<usize>,
+    leftover: Leftover,
+    syntax: &CommentSyntax,
+    snippet: &str,
+) -> Option<(Range<usize>, String)> {
+    match leftover {
+        Leftover::Keep => None,
+        Leftover::Strip => {
+            let line_start = source[..comment.start].rfind('\n').map_or(0, |i| i + 1);
+            let line_end = source[comment.end..]
+                .find('\n')
+                .map_or(source.len(), |i| comment.end + i);
+            let before = &source[line_start..comment.start];
+            let start = comment.start - (before.len() - before.trim_end().len());
+            if before.trim().is_empty() && source[comment.end..line_end].trim().is_empty() {
+                let end = (line_end + 1).min(source.len());
+                Some((line_start..end, String::new()))
+            } else {
+                Some((start..comment.end, String::new()))
+            }
+        }
+        Leftover::Audit => {
+            let audit = if let Some(token) = syntax.line.first() {
+                format!("{token} silos snippet: {snippet}")
+            } else if let Some((start, end)) = syntax.block.first() {
+                format!("{start} silos snippet: {snippet} {end}")
+            } else {
+                return None;
+            };
+            Some((comment.clone(), audit))
+        }
+    }
+}
+
+/// The comment describing the code in `selection`: either comment lines opening
+/// the selection or, failing that, those right above it. A generate trigger in it
+/// is dropped. Returns the description and the range of the comment.
+pub fn heading_comment(
+    language: &Language,
+    source: &str,
+    selection: &Range<usize>,
+    triggers: &Triggers,
+) -> Option<(String, Range<usize>)> {
+    let syntax = &language.comments;
+    let is_comment = |line: &str| {
+        let line = line.trim();
+        syntax
+            .line
+            .iter()
+            .any(|token| line.starts_with(token.as_str()))
+            || syntax.block.iter().any(|(start, end)| {
+                line.starts_with(start.as_str()) && line.ends_with(end.as_str())
+            })
+    };
+
+    let line_start = source[..selection.start]
+        .rfind('\n')
+        .map_or(0, |newline| newline + 1);
+    let end = line_start
+        + source[line_start..selection.end]
+            .split_inclusive('\n')
+            .take_while(|line| is_comment(line))
+            .map(str::len)
+            .sum::<usize>();
+    let start = if end > line_start {
+        line_start
+    } else {
+        line_start
+            - source[..line_start]
+                .split_inclusive('\n')
+                .rev()
+                .take_while(|line| is_comment(line))
+                .map(str::len)
+                .sum::<usize>()
+    };
+    if start == end {
+        return None;
+    }
+
+    let description = comment_lines(&source[start..end], syntax)
+        .into_iter()
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let description = triggers
+        .generate
+        .iter()
+        .find_map(|prefix| description.strip_prefix(prefix.as_str()))
+        .unwrap_or(&description)
+        .trim()
+        .to_string();
+    (!description.is_empty()).then_some((description, start..end))
+}
+
+fn collect_comments<'a>(node: Node<'a>, selection: &Range<usize>, comments: &mut Vec<Node<'a>>) {
+    // a bare cursor touching either end of a comment still counts
+    if node.end_byte() < selection.start || selection.end < node.start_byte() {
+        return;
+    }
+    if is_comment(node) {
+        comments.push(node);
+        return;
+    }
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        collect_comments(child, selection, comments);
+    }
+}
+
+fn is_comment(node: Node<'_>) -> bool {
+    if node.kind().contains("comment") {
+        return true;
+    }
+    // a string standing on its own as a statement is a docstring
+    node.kind() == "string"
+        && node.parent().is_some_and(|parent| {
+            parent.kind() == "expression_statement" && parent.named_child_count() == 1
+        })
+}
+
+fn parse_comment(
+    source: &str,
+    comment: Range<usize>,
+    syntax: &CommentSyntax,
+    triggers: &Triggers,
+) -> Option<ParsedAction> {
+    let lines = comment_lines(&source[comment.clone()], syntax);
+    let actions = [
+        (Action::Generate, &triggers.generate),
+        (Action::Refactor, &triggers.refactor),
+    ];
+    for (i, line) in lines.iter().enumerate() {
+        for (action, prefixes) in actions {
+            let Some(rest) = prefixes
+                .iter()
+                .find_map(|prefix| line.strip_prefix(prefix.as_str()))
+            else {
+                continue;
+            };
+            // the prompt carries on until the end of a block comment
+            let description = std::iter::once(rest)
+                .chain(lines[i + 1..].iter().copied())
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ");
+            return Some(ParsedAction {
+                action,
+                description,
+                comment,
+                target: None,
+            });
+        }
+    }
+    None
+}
+
+// the text of a comment, line by line, without the comment syntax around it
+fn comment_lines<'a>(text: &'a str, syntax: &CommentSyntax) -> Vec<&'a str> {
+    let mut text = text.trim();
+    let mut block = false;
+    for (start, end) in &syntax.block {
+        if let Some(inner) = text
+            .strip_prefix(start.as_str())
+            .and_then(|text| text.strip_suffix(end.as_str()))
+        {
+            text = inner;
+            block = true;
+            break;
+        }
+    }
+
+    text.lines()
+        .map(|line| {
+            let line = line.trim();
+            if block {
+                return line.trim_start_matches('*').trim();
+            }
+            syntax
+                .line
+                .iter()
+                .find_map(|token| {
+                    // also covers doubled up tokens such as `///` or `##`
+                    line.strip_prefix(token.as_str())
+                        .map(|rest| rest.trim_start_matches(|c| token.contains(c)))
+                })
+                .unwrap_or(line)
+                .trim()
+        })
+        .collect()
+}